@@ -0,0 +1,4699 @@
+use serde_derive::Deserialize;
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, HashMap},
+    io::{self, Write},
+    rc::Rc,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    time::Instant,
+};
+
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub enum Expr {
+    Application(Vec<Expr>),
+    Identifier(String),
+    Cond(Vec<Expr>),
+    Block(Vec<Expr>),
+    Clause(Vec<Expr>),
+    Number(i64),
+    // A number written as a string, so programs can use "0xFF", "0b101" or "-0o17"
+    NumberStr(String),
+    Float(f64),
+    Bool(bool),
+    String(String),
+    Parameters(Vec<Expr>),
+    Lambda(Vec<Expr>),
+    Let(Box<Expr>, Box<Expr>, Box<Expr>),
+    Define(Box<Expr>, Box<Expr>),
+    Assignment(Box<Expr>, Box<Expr>),
+    If(Box<Expr>, Box<Expr>, Box<Expr>),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+    LetStar(Vec<Expr>, Box<Expr>),
+    LetMany(Vec<Expr>, Box<Expr>),
+    Set(Box<Expr>, Box<Expr>),
+    While(Box<Expr>, Box<Expr>),
+    Begin(Vec<Expr>),
+    Quote(Box<Expr>),
+    LetRec(Vec<Expr>, Box<Expr>),
+    When(Box<Expr>, Box<Expr>),
+    Unless(Box<Expr>, Box<Expr>),
+    IfLet(Box<Expr>, Box<Expr>, Box<Expr>, Box<Expr>),
+    Defined(Box<Expr>),
+    Do(Box<Expr>, Box<Expr>, Box<Expr>, Box<Expr>),
+    Try(Box<Expr>, Box<Expr>),
+    Time(Box<Expr>),
+    DumpEnv,
+    Case(Box<Expr>, Vec<Expr>),
+    Scope(Vec<Expr>),
+}
+
+
+
+#[derive(Debug, Clone)]
+pub enum ResultValue {
+    // "No meaningful value", returned by side-effecting forms like define and print
+    Nil,
+    Number(i64),
+    // An exact fraction in lowest terms with a positive denominator other than 1
+    Rational(i64, i64),
+    Float(f64),
+    Bool(bool),
+    String(String),
+    Char(char),
+    // Builtins carry the name they're registered under, so arity errors can name them even when
+    // they're called through map, apply and the like
+    Func(Rc<str>, usize, Builtin),
+    // Variadic builtins: no arity check, the function validates its own argument list
+    VarFunc(Builtin),
+    // Builtins that need the evaluator, e.g. to call a function argument
+    EnvFunc(Rc<str>, usize, fn(Vec<ResultValue>, &SharedEnv) -> Result<ResultValue, EvalError>),
+    // Closures share their defining scope rather than copying it. The body is shared by every copy
+    // of the closure, which also gives it an identity for `equal?`. The name is the one the lambda
+    // was first bound to, if any.
+    Lambda(Option<Rc<str>>, Vec<String>, Rc<Expr>, SharedEnv),
+    List(Vec<ResultValue>),
+    // A cons cell whose tail isn't a list, e.g. cons(1, 2); proper lists stay `List`
+    Pair(Box<ResultValue>, Box<ResultValue>),
+    Map(HashMap<String, ResultValue>),
+    Symbol(String),
+    // compose(f, g): calls g with the arguments, then f with g's result
+    Composed(Rc<ResultValue>, Rc<ResultValue>),
+    // memoize(f): results of f by argument, shared by every copy of the memoized function
+    Memoized(Box<ResultValue>, Rc<RefCell<HashMap<String, ResultValue>>>),
+    // curry(f, a, b): calls f with a and b ahead of whatever arguments it's given
+    Curried(Rc<ResultValue>, Vec<ResultValue>),
+}
+
+impl std::fmt::Display for ResultValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.format(f, false)
+    }
+}
+
+// The written form of a value, as the result printer shows it: unlike Display (used by print and
+// display), strings are quoted and escaped, so "a b" can't be confused with two values
+pub struct Written<'a>(pub &'a ResultValue);
+
+impl std::fmt::Display for Written<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.format(f, true)
+    }
+}
+
+impl ResultValue {
+    fn format(&self, f: &mut std::fmt::Formatter<'_>, written: bool) -> std::fmt::Result {
+        match self {
+            ResultValue::Nil => write!(f, "nil"),
+            ResultValue::Number(n) => write!(f, "{}", n),
+            ResultValue::Rational(n, d) => write!(f, "{}/{}", n, d),
+            // Floats always keep a fractional part (3.0, not 3) so they can't be mistaken for integers
+            ResultValue::Float(x) => write!(f, "{:?}", x),
+            ResultValue::Bool(b) => write!(f, "{}", b),
+            ResultValue::String(s) if written => write!(f, "{}", escape_string(s)),
+            ResultValue::String(s) => write!(f, "{}", s),
+            ResultValue::Char(c) => write!(f, "{}", c),
+            ResultValue::Func(_, _, _) => write!(f, "<function>"),
+            ResultValue::VarFunc(_) => write!(f, "<function>"),
+            ResultValue::EnvFunc(_, _, _) => write!(f, "<function>"),
+            ResultValue::Composed(_, _) => write!(f, "<function>"),
+            ResultValue::Memoized(_, _) => write!(f, "<function>"),
+            ResultValue::Curried(_, _) => write!(f, "<function>"),
+            ResultValue::Lambda(_, p, b, _) => write!(f, "<lambda {:?} {:?}>", p, b),
+            ResultValue::Symbol(name) => write!(f, "{}", name),
+            ResultValue::List(items) => {
+                write!(f, "(")?;
+                for (index, item) in items.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, " ")?;
+                    }
+                    item.format(f, written)?;
+                }
+                write!(f, ")")
+            }
+            // A chain of pairs prints as one list, dotted before a final tail that isn't a list: (1 2 . 3)
+            ResultValue::Pair(head, tail) => {
+                write!(f, "(")?;
+                head.format(f, written)?;
+                let mut tail = tail.as_ref();
+                while let ResultValue::Pair(head, rest) = tail {
+                    write!(f, " ")?;
+                    head.format(f, written)?;
+                    tail = rest;
+                }
+                match tail {
+                    ResultValue::List(items) => {
+                        for item in items {
+                            write!(f, " ")?;
+                            item.format(f, written)?;
+                        }
+                        write!(f, ")")
+                    }
+                    _ => {
+                        write!(f, " . ")?;
+                        tail.format(f, written)?;
+                        write!(f, ")")
+                    }
+                }
+            }
+            // Keys are sorted so the same map always prints the same way
+            ResultValue::Map(entries) => {
+                let mut keys: Vec<&String> = entries.keys().collect();
+                keys.sort();
+                write!(f, "{{")?;
+                for (index, key) in keys.into_iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+                    if written {
+                        write!(f, "{}: ", escape_string(key))?;
+                    } else {
+                        write!(f, "{}: ", key)?;
+                    }
+                    entries[key].format(f, written)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+// Wraps a string in double quotes, escaping quotes, backslashes and line breaks
+fn escape_string(s: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+impl ResultValue {
+    // Structured form for `--json` output: one key naming the variant, holding its contents
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            ResultValue::Nil => serde_json::json!({ "Nil": null }),
+            ResultValue::Number(n) => serde_json::json!({ "Number": n }),
+            ResultValue::Rational(n, d) => serde_json::json!({ "Rational": [n, d] }),
+            // JSON has no NaN or infinity (serde_json would quietly write null), so those are tagged
+            // with their printed form instead
+            ResultValue::Float(x) if !x.is_finite() => serde_json::json!({ "Float": format!("{:?}", x) }),
+            ResultValue::Float(x) => serde_json::json!({ "Float": x }),
+            ResultValue::Bool(b) => serde_json::json!({ "Bool": b }),
+            ResultValue::String(s) => serde_json::json!({ "String": s }),
+            ResultValue::Char(c) => serde_json::json!({ "Char": c }),
+            ResultValue::Symbol(name) => serde_json::json!({ "Symbol": name }),
+            ResultValue::List(items) => {
+                let items: Vec<serde_json::Value> = items.iter().map(|item| item.to_json()).collect();
+                serde_json::json!({ "List": items })
+            }
+            ResultValue::Pair(head, tail) => serde_json::json!({ "Pair": [head.to_json(), tail.to_json()] }),
+            ResultValue::Map(entries) => {
+                let entries: serde_json::Map<String, serde_json::Value> =
+                    entries.iter().map(|(key, value)| (key.clone(), value.to_json())).collect();
+                serde_json::json!({ "Map": entries })
+            }
+            ResultValue::Lambda(_, params, _, _) => serde_json::json!({ "Lambda": params }),
+            ResultValue::Func(_, _, _)
+            | ResultValue::VarFunc(_)
+            | ResultValue::EnvFunc(_, _, _)
+            | ResultValue::Composed(_, _)
+            | ResultValue::Memoized(_, _)
+            | ResultValue::Curried(_, _) => serde_json::json!({ "Function": null }),
+        }
+    }
+}
+
+// An evaluation error plus the path of expressions it was raised under, outermost first
+#[derive(Debug, Clone)]
+pub struct EvalError {
+    pub message: String,
+    pub path: Vec<String>,
+}
+
+impl EvalError {
+    // Records that the error happened inside `frame`; called as the error propagates outwards
+    fn within(mut self, frame: impl Into<String>) -> Self {
+        self.path.insert(0, frame.into());
+        self
+    }
+}
+
+impl From<String> for EvalError {
+    fn from(message: String) -> Self {
+        EvalError { message, path: Vec::new() }
+    }
+}
+
+impl ResultValue {
+    fn is_callable(&self) -> bool {
+        matches!(
+            self,
+            ResultValue::Func(_, _, _)
+                | ResultValue::VarFunc(_)
+                | ResultValue::EnvFunc(_, _, _)
+                | ResultValue::Lambda(_, _, _, _)
+                | ResultValue::Composed(_, _)
+                | ResultValue::Memoized(_, _)
+                | ResultValue::Curried(_, _)
+        )
+    }
+
+    // Everything but false and nil counts as true where a form accepts any value as a test
+    fn is_truthy(&self) -> bool {
+        !matches!(self, ResultValue::Bool(false) | ResultValue::Nil)
+    }
+
+    // The name arity errors report for a callable; anonymous lambdas have none
+    fn name(&self) -> Option<&str> {
+        match self {
+            ResultValue::Func(name, _, _) | ResultValue::EnvFunc(name, _, _) => Some(name),
+            ResultValue::Lambda(name, _, _, _) => name.as_deref(),
+            ResultValue::Memoized(f, _) => f.name(),
+            _ => None,
+        }
+    }
+
+    // Gives an anonymous lambda the name it's being bound to or called through; anything else,
+    // including a lambda that already has a name, is returned unchanged
+    fn named(self, name: &str) -> ResultValue {
+        match self {
+            ResultValue::Lambda(None, param_names, body, env) => ResultValue::Lambda(Some(name.into()), param_names, body, env),
+            value => value,
+        }
+    }
+
+    // Numeric view used when promoting mixed Number/Float arguments
+    fn as_float(&self) -> Option<f64> {
+        match self {
+            ResultValue::Number(n) => Some(*n as f64),
+            ResultValue::Rational(n, d) => Some(*n as f64 / *d as f64),
+            ResultValue::Float(x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    // Numerator and denominator of an exact number, widened so products of two can't overflow
+    fn as_ratio(&self) -> Option<(i128, i128)> {
+        match self {
+            ResultValue::Number(n) => Some((*n as i128, 1)),
+            ResultValue::Rational(n, d) => Some((*n as i128, *d as i128)),
+            _ => None,
+        }
+    }
+
+    // Structural equality for `equal?`: lists and maps compare element by element, and values of
+    // different types (including 1 and 1.0) are unequal. A function is equal only to itself, however
+    // it was passed around: builtins compare by function pointer, other functions by the allocation
+    // their copies share, so two lambdas with the same code are still different functions.
+    fn deep_equal(&self, other: &ResultValue) -> bool {
+        match (self, other) {
+            (ResultValue::Nil, ResultValue::Nil) => true,
+            (ResultValue::Number(a), ResultValue::Number(b)) => a == b,
+            (ResultValue::Rational(a, b), ResultValue::Rational(c, d)) => (a, b) == (c, d),
+            (ResultValue::Float(a), ResultValue::Float(b)) => a == b,
+            (ResultValue::Bool(a), ResultValue::Bool(b)) => a == b,
+            (ResultValue::String(a), ResultValue::String(b)) => a == b,
+            (ResultValue::Char(a), ResultValue::Char(b)) => a == b,
+            (ResultValue::Symbol(a), ResultValue::Symbol(b)) => a == b,
+            (ResultValue::List(a), ResultValue::List(b)) => {
+                a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.deep_equal(b))
+            }
+            (ResultValue::Pair(a_head, a_tail), ResultValue::Pair(b_head, b_tail)) => {
+                a_head.deep_equal(b_head) && a_tail.deep_equal(b_tail)
+            }
+            (ResultValue::Map(a), ResultValue::Map(b)) => {
+                a.len() == b.len() && a.iter().all(|(key, value)| b.get(key).is_some_and(|other| value.deep_equal(other)))
+            }
+            (ResultValue::Func(_, _, f), ResultValue::Func(_, _, g)) => std::ptr::fn_addr_eq(*f, *g),
+            (ResultValue::VarFunc(f), ResultValue::VarFunc(g)) => std::ptr::fn_addr_eq(*f, *g),
+            (ResultValue::EnvFunc(_, _, f), ResultValue::EnvFunc(_, _, g)) => std::ptr::fn_addr_eq(*f, *g),
+            (ResultValue::Lambda(_, _, a, _), ResultValue::Lambda(_, _, b, _)) => Rc::ptr_eq(a, b),
+            (ResultValue::Composed(f, g), ResultValue::Composed(h, k)) => Rc::ptr_eq(f, h) && Rc::ptr_eq(g, k),
+            (ResultValue::Memoized(_, a), ResultValue::Memoized(_, b)) => Rc::ptr_eq(a, b),
+            (ResultValue::Curried(f, _), ResultValue::Curried(g, _)) => Rc::ptr_eq(f, g),
+            _ => false,
+        }
+    }
+}
+
+// Scopes are shared so closures and child scopes see each other's updates
+pub type SharedEnv = Rc<RefCell<Env>>;
+
+// A plain builtin: takes the evaluated arguments and returns a value or an error message
+pub type Builtin = fn(Vec<ResultValue>) -> Result<ResultValue, String>;
+
+#[derive(Debug)]
+pub struct Env {
+    vars: HashMap<String, ResultValue>,
+    // Only populated in the root scope; child scopes find builtins through their parents
+    builtins: HashMap<String, ResultValue>,
+    parent: Option<SharedEnv>,
+}
+
+// The variables of a scope and each of its parents, for rolling an environment back later. Only that
+// chain is captured: scopes hanging off it, like those of closures created in it, are not. This is not
+// a cheap copy: every variable's value is cloned, lists and maps element by element, so a snapshot
+// costs time and memory in proportion to what's bound. The builtins are kept apart from the variables
+// and are never copied.
+#[derive(Debug, Clone)]
+pub struct EnvSnapshot {
+    vars: HashMap<String, ResultValue>,
+    parent: Option<Box<EnvSnapshot>>,
+}
+
+impl Env {
+    // The root scope, with the standard builtins plus any the host program adds as (name, arity, function)
+    pub fn new(extra_builtins: &[(&str, usize, Builtin)]) -> Self {
+        // Initialize the environment with built-in functions
+        let mut builtins = HashMap::new();
+        builtins.insert(
+            "add".to_string(),
+            ResultValue::VarFunc(|args| {
+                // Variadic: add() is 0, and further arguments are folded in from the left
+                args.into_iter().try_fold(ResultValue::Number(0), |acc, arg| match (acc, arg) {
+                    (ResultValue::Number(a), ResultValue::Number(b)) => a
+                        .checked_add(b)
+                        .map(ResultValue::Number)
+                        .ok_or_else(|| "Integer overflow".to_string()),
+                    (a, b) => match (exact_pair(&a, &b), a.as_float(), b.as_float()) {
+                        (Some(((an, ad), (bn, bd))), _, _) => rational(an * bd + bn * ad, ad * bd),
+                        (None, Some(a), Some(b)) => Ok(ResultValue::Float(a + b)),
+                        _ => Err("Invalid arguments".to_string()),
+                    },
+                })
+            }),
+        );
+        builtins.insert(
+            "sub".to_string(),
+            ResultValue::Func("sub".into(), 2, |args| {
+                if args.len() != 2 {
+                    return Err("Expected exactly 2 arguments".to_string());
+                }
+
+                match (args[0].clone(), args[1].clone()) {
+                    (ResultValue::Number(a), ResultValue::Number(b)) => a
+                        .checked_sub(b)
+                        .map(ResultValue::Number)
+                        .ok_or_else(|| "Integer overflow".to_string()),
+                    (a, b) => match (exact_pair(&a, &b), a.as_float(), b.as_float()) {
+                        (Some(((an, ad), (bn, bd))), _, _) => rational(an * bd - bn * ad, ad * bd),
+                        (None, Some(a), Some(b)) => Ok(ResultValue::Float(a - b)),
+                        _ => Err("Invalid arguments".to_string()),
+                    },
+                }
+            }),
+        );
+        builtins.insert(
+            "mul".to_string(),
+            ResultValue::VarFunc(|args| {
+                // Variadic: mul() is 1, and further arguments are folded in from the left
+                args.into_iter().try_fold(ResultValue::Number(1), |acc, arg| match (acc, arg) {
+                    (ResultValue::Number(a), ResultValue::Number(b)) => a
+                        .checked_mul(b)
+                        .map(ResultValue::Number)
+                        .ok_or_else(|| "Integer overflow".to_string()),
+                    (a, b) => match (exact_pair(&a, &b), a.as_float(), b.as_float()) {
+                        (Some(((an, ad), (bn, bd))), _, _) => rational(an * bn, ad * bd),
+                        (None, Some(a), Some(b)) => Ok(ResultValue::Float(a * b)),
+                        _ => Err("Invalid arguments".to_string()),
+                    },
+                })
+            }),
+        );
+        builtins.insert(
+            "div".to_string(),
+            ResultValue::Func("div".into(), 2, |args| {
+                if args.len() != 2 {
+                    return Err("Expected exactly 2 arguments".to_string());
+                }
+
+                // Two Numbers still divide as integers; a Rational operand makes the result exact
+                match (args[0].clone(), args[1].clone()) {
+                    (ResultValue::Number(a), ResultValue::Number(b)) => {
+                        if b == 0 {
+                            Err("Division by zero".to_string())
+                        } else {
+                            a.checked_div(b).map(ResultValue::Number).ok_or_else(|| "Arithmetic overflow".to_string())
+                        }
+                    }
+                    (a, b) => match (exact_pair(&a, &b), a.as_float(), b.as_float()) {
+                        (Some((_, (0, _))), _, _) => Err("Division by zero".to_string()),
+                        (Some(((an, ad), (bn, bd))), _, _) => rational(an * bd, ad * bn),
+                        (None, Some(a), Some(b)) => {
+                            if b == 0.0 {
+                                Err("Division by zero".to_string())
+                            } else {
+                                Ok(ResultValue::Float(a / b))
+                            }
+                        }
+                        _ => Err("Invalid arguments".to_string()),
+                    },
+                }
+            }),
+        );
+        builtins.insert(
+            "fdiv".to_string(),
+            ResultValue::Func("fdiv".into(), 2, |args| {
+                if args.len() != 2 {
+                    return Err("Expected exactly 2 arguments".to_string());
+                }
+
+                // Like div, but always produces a Float so inexact integer division keeps its fraction
+                match (args[0].as_float(), args[1].as_float()) {
+                    (Some(a), Some(b)) => {
+                        if b == 0.0 {
+                            Err("Division by zero".to_string())
+                        } else {
+                            Ok(ResultValue::Float(a / b))
+                        }
+                    }
+                    _ => Err("Invalid arguments".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "mod".to_string(),
+            ResultValue::Func("mod".into(), 2, |args| {
+                if args.len() != 2 {
+                    return Err("Expected exactly 2 arguments".to_string());
+                }
+
+                // Euclidean modulo: the result is never negative, so mod(-7, 3) is 2
+                match (args[0].clone(), args[1].clone()) {
+                    (ResultValue::Number(a), ResultValue::Number(b)) => {
+                        if b == 0 {
+                            Err("Modulo by zero".to_string())
+                        } else {
+                            a.checked_rem_euclid(b).map(ResultValue::Number).ok_or_else(|| "Arithmetic overflow".to_string())
+                        }
+                    }
+                    _ => Err("Invalid arguments".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "rem".to_string(),
+            ResultValue::Func("rem".into(), 2, |args| {
+                if args.len() != 2 {
+                    return Err("Expected exactly 2 arguments".to_string());
+                }
+
+                // Truncated remainder: takes the sign of the dividend, so rem(-7, 3) is -1
+                match (args[0].clone(), args[1].clone()) {
+                    (ResultValue::Number(a), ResultValue::Number(b)) => {
+                        if b == 0 {
+                            Err("Modulo by zero".to_string())
+                        } else {
+                            a.checked_rem(b).map(ResultValue::Number).ok_or_else(|| "Arithmetic overflow".to_string())
+                        }
+                    }
+                    _ => Err("Invalid arguments".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "pow".to_string(),
+            ResultValue::Func("pow".into(), 2, |args| {
+                if args.len() != 2 {
+                    return Err("Expected exactly 2 arguments".to_string());
+                }
+
+                match (args[0].clone(), args[1].clone()) {
+                    (ResultValue::Number(a), ResultValue::Number(b)) => {
+                        if b < 0 {
+                            return Err("Negative exponent".to_string());
+                        }
+                        u32::try_from(b)
+                            .ok()
+                            .and_then(|b| a.checked_pow(b))
+                            .map(ResultValue::Number)
+                            .ok_or_else(|| "Integer overflow".to_string())
+                    }
+                    (a, b) => match (a.as_float(), b.as_float()) {
+                        (Some(a), Some(b)) => Ok(ResultValue::Float(a.powf(b))),
+                        _ => Err("Invalid arguments".to_string()),
+                    },
+                }
+            }),
+        );
+        builtins.insert(
+            "rational".to_string(),
+            ResultValue::Func("rational".into(), 2, |args| {
+                if args.len() != 2 {
+                    return Err("Expected exactly 2 arguments".to_string());
+                }
+
+                match (args[0].clone(), args[1].clone()) {
+                    (ResultValue::Number(n), ResultValue::Number(d)) => rational(n as i128, d as i128),
+                    _ => Err("Invalid arguments".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "gcd".to_string(),
+            ResultValue::Func("gcd".into(), 2, |args| {
+                if args.len() != 2 {
+                    return Err("Expected exactly 2 arguments".to_string());
+                }
+
+                match (args[0].clone(), args[1].clone()) {
+                    (ResultValue::Number(a), ResultValue::Number(b)) => i64::try_from(gcd(a, b))
+                        .map(ResultValue::Number)
+                        .map_err(|_| "Integer overflow".to_string()),
+                    _ => Err("Invalid arguments".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "lcm".to_string(),
+            ResultValue::Func("lcm".into(), 2, |args| {
+                if args.len() != 2 {
+                    return Err("Expected exactly 2 arguments".to_string());
+                }
+
+                match (args[0].clone(), args[1].clone()) {
+                    (ResultValue::Number(0), ResultValue::Number(_)) | (ResultValue::Number(_), ResultValue::Number(0)) => {
+                        Ok(ResultValue::Number(0))
+                    }
+                    (ResultValue::Number(a), ResultValue::Number(b)) => (a.unsigned_abs() / gcd(a, b))
+                        .checked_mul(b.unsigned_abs())
+                        .and_then(|n| i64::try_from(n).ok())
+                        .map(ResultValue::Number)
+                        .ok_or_else(|| "Integer overflow".to_string()),
+                    _ => Err("Invalid arguments".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "isqrt".to_string(),
+            ResultValue::Func("isqrt".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                // Floor of the square root, computed exactly on integers
+                match args[0].clone() {
+                    ResultValue::Number(n) if n < 0 => Err("isqrt of negative".to_string()),
+                    ResultValue::Number(n) => Ok(ResultValue::Number(n.isqrt())),
+                    _ => Err("Invalid argument".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "sqrt".to_string(),
+            ResultValue::Func("sqrt".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                match args[0].as_float() {
+                    Some(x) if x < 0.0 => Err("sqrt of negative".to_string()),
+                    Some(x) => Ok(ResultValue::Float(x.sqrt())),
+                    None => Err("Invalid argument".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "factorial".to_string(),
+            ResultValue::Func("factorial".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                match args[0].clone() {
+                    ResultValue::Number(n) if n < 0 => Err("factorial of negative".to_string()),
+                    ResultValue::Number(n) => (1..=n)
+                        .try_fold(1i64, |product, k| product.checked_mul(k))
+                        .map(ResultValue::Number)
+                        .ok_or_else(|| "Integer overflow".to_string()),
+                    _ => Err("Invalid argument".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "bit-and".to_string(),
+            ResultValue::Func("bit-and".into(), 2, |args| {
+                if args.len() != 2 {
+                    return Err("Expected exactly 2 arguments".to_string());
+                }
+
+                match (args[0].clone(), args[1].clone()) {
+                    (ResultValue::Number(a), ResultValue::Number(b)) => Ok(ResultValue::Number(a & b)),
+                    _ => Err("Invalid arguments".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "bit-or".to_string(),
+            ResultValue::Func("bit-or".into(), 2, |args| {
+                if args.len() != 2 {
+                    return Err("Expected exactly 2 arguments".to_string());
+                }
+
+                match (args[0].clone(), args[1].clone()) {
+                    (ResultValue::Number(a), ResultValue::Number(b)) => Ok(ResultValue::Number(a | b)),
+                    _ => Err("Invalid arguments".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "bit-xor".to_string(),
+            ResultValue::Func("bit-xor".into(), 2, |args| {
+                if args.len() != 2 {
+                    return Err("Expected exactly 2 arguments".to_string());
+                }
+
+                match (args[0].clone(), args[1].clone()) {
+                    (ResultValue::Number(a), ResultValue::Number(b)) => Ok(ResultValue::Number(a ^ b)),
+                    _ => Err("Invalid arguments".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "bit-not".to_string(),
+            ResultValue::Func("bit-not".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                match args[0].clone() {
+                    ResultValue::Number(n) => Ok(ResultValue::Number(!n)),
+                    _ => Err("Invalid argument".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "shift-left".to_string(),
+            ResultValue::Func("shift-left".into(), 2, |args| {
+                if args.len() != 2 {
+                    return Err("Expected exactly 2 arguments".to_string());
+                }
+
+                match (args[0].clone(), args[1].clone()) {
+                    (ResultValue::Number(a), ResultValue::Number(count)) => u32::try_from(count)
+                        .ok()
+                        .and_then(|count| a.checked_shl(count))
+                        .map(ResultValue::Number)
+                        .ok_or_else(|| "invalid shift amount".to_string()),
+                    _ => Err("Invalid arguments".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "shift-right".to_string(),
+            ResultValue::Func("shift-right".into(), 2, |args| {
+                if args.len() != 2 {
+                    return Err("Expected exactly 2 arguments".to_string());
+                }
+
+                // Arithmetic shift: negative numbers keep their sign
+                match (args[0].clone(), args[1].clone()) {
+                    (ResultValue::Number(a), ResultValue::Number(count)) => u32::try_from(count)
+                        .ok()
+                        .and_then(|count| a.checked_shr(count))
+                        .map(ResultValue::Number)
+                        .ok_or_else(|| "invalid shift amount".to_string()),
+                    _ => Err("Invalid arguments".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "min".to_string(),
+            ResultValue::VarFunc(|args| {
+                if args.is_empty() {
+                    return Err("Expected at least 1 argument".to_string());
+                }
+
+                args.into_iter()
+                    .try_fold(i64::MAX, |acc, arg| match arg {
+                        ResultValue::Number(n) => Ok(acc.min(n)),
+                        _ => Err("Invalid arguments".to_string()),
+                    })
+                    .map(ResultValue::Number)
+            }),
+        );
+        builtins.insert(
+            "max".to_string(),
+            ResultValue::VarFunc(|args| {
+                if args.is_empty() {
+                    return Err("Expected at least 1 argument".to_string());
+                }
+
+                args.into_iter()
+                    .try_fold(i64::MIN, |acc, arg| match arg {
+                        ResultValue::Number(n) => Ok(acc.max(n)),
+                        _ => Err("Invalid arguments".to_string()),
+                    })
+                    .map(ResultValue::Number)
+            }),
+        );
+        builtins.insert(
+            "abs".to_string(),
+            ResultValue::Func("abs".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                // abs(i64::MIN) has no i64 representation, so it errors instead of wrapping
+                match args[0].clone() {
+                    ResultValue::Number(n) => n
+                        .checked_abs()
+                        .map(ResultValue::Number)
+                        .ok_or_else(|| "Integer overflow".to_string()),
+                    _ => Err("Invalid argument".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "neg".to_string(),
+            ResultValue::Func("neg".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                match args[0].clone() {
+                    ResultValue::Number(n) => n
+                        .checked_neg()
+                        .map(ResultValue::Number)
+                        .ok_or_else(|| "Integer overflow".to_string()),
+                    _ => Err("Invalid argument".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "sign".to_string(),
+            ResultValue::Func("sign".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                match args[0].clone() {
+                    ResultValue::Number(n) => Ok(ResultValue::Number(n.signum())),
+                    _ => Err("Invalid argument".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "zero?".to_string(),
+            ResultValue::Func("zero?".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                match args[0].clone() {
+                    ResultValue::Number(n) => Ok(ResultValue::Bool(n == 0)),
+                    _ => Err("Invalid argument".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "even?".to_string(),
+            ResultValue::Func("even?".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                match args[0].clone() {
+                    ResultValue::Number(n) => Ok(ResultValue::Bool(n % 2 == 0)),
+                    _ => Err("Invalid argument".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "odd?".to_string(),
+            ResultValue::Func("odd?".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                match args[0].clone() {
+                    ResultValue::Number(n) => Ok(ResultValue::Bool(n % 2 != 0)),
+                    _ => Err("Invalid argument".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "positive?".to_string(),
+            ResultValue::Func("positive?".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                match args[0].clone() {
+                    ResultValue::Number(n) => Ok(ResultValue::Bool(n > 0)),
+                    _ => Err("Invalid argument".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "negative?".to_string(),
+            ResultValue::Func("negative?".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                match args[0].clone() {
+                    ResultValue::Number(n) => Ok(ResultValue::Bool(n < 0)),
+                    _ => Err("Invalid argument".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "not".to_string(),
+            ResultValue::Func("not".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                match args[0].clone() {
+                    ResultValue::Bool(b) => Ok(ResultValue::Bool(!b)),
+                    _ => Err("Invalid argument".to_string()),
+                }
+            }),
+        );
+        // Type predicates accept a value of any type and never fail on it
+        builtins.insert(
+            "number?".to_string(),
+            ResultValue::Func("number?".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                Ok(ResultValue::Bool(matches!(args[0], ResultValue::Number(_) | ResultValue::Rational(_, _) | ResultValue::Float(_))))
+            }),
+        );
+        builtins.insert(
+            "nil?".to_string(),
+            ResultValue::Func("nil?".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                Ok(ResultValue::Bool(matches!(args[0], ResultValue::Nil)))
+            }),
+        );
+        builtins.insert(
+            "string?".to_string(),
+            ResultValue::Func("string?".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                Ok(ResultValue::Bool(matches!(args[0], ResultValue::String(_))))
+            }),
+        );
+        builtins.insert(
+            "bool?".to_string(),
+            ResultValue::Func("bool?".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                Ok(ResultValue::Bool(matches!(args[0], ResultValue::Bool(_))))
+            }),
+        );
+        builtins.insert(
+            "function?".to_string(),
+            ResultValue::Func("function?".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                Ok(ResultValue::Bool(args[0].is_callable()))
+            }),
+        );
+        builtins.insert(
+            "equal".to_string(),
+            ResultValue::Func("equal".into(), 2, |args| {
+                if args.len() != 2 {
+                    return Err("Expected exactly 2 arguments".to_string());
+                }
+
+                match (args[0].clone(), args[1].clone()) {
+                    // Numbers of any kind compare by value, so 1 equals 1.0 (and a NaN equals nothing)
+                    (a, b) if a.as_float().is_some() && b.as_float().is_some() => {
+                        Ok(ResultValue::Bool(compare_numbers(&a, &b) == Some(std::cmp::Ordering::Equal)))
+                    }
+                    (ResultValue::Bool(a), ResultValue::Bool(b)) => Ok(ResultValue::Bool(a == b)),
+                    (ResultValue::String(a), ResultValue::String(b)) => Ok(ResultValue::Bool(a == b)),
+                    (ResultValue::Char(a), ResultValue::Char(b)) => Ok(ResultValue::Bool(a == b)),
+                    (ResultValue::Symbol(a), ResultValue::Symbol(b)) => Ok(ResultValue::Bool(a == b)),
+                    // nil only equals itself; it's never an error to compare against it
+                    (ResultValue::Nil, ResultValue::Nil) => Ok(ResultValue::Bool(true)),
+                    (ResultValue::Nil, _) | (_, ResultValue::Nil) => Ok(ResultValue::Bool(false)),
+                    _ => Err("Invalid arguments".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "equal?".to_string(),
+            ResultValue::Func("equal?".into(), 2, |args| {
+                if args.len() != 2 {
+                    return Err("Expected exactly 2 arguments".to_string());
+                }
+
+                Ok(ResultValue::Bool(args[0].deep_equal(&args[1])))
+            }),
+        );
+        builtins.insert(
+            "!=".to_string(),
+            ResultValue::Func("!=".into(), 2, |args| {
+                if args.len() != 2 {
+                    return Err("Expected exactly 2 arguments".to_string());
+                }
+
+                match (args[0].clone(), args[1].clone()) {
+                    // Numbers of any kind compare by value, so 1 equals 1.0 (and a NaN equals nothing)
+                    (a, b) if a.as_float().is_some() && b.as_float().is_some() => {
+                        Ok(ResultValue::Bool(compare_numbers(&a, &b) != Some(std::cmp::Ordering::Equal)))
+                    }
+                    (ResultValue::Bool(a), ResultValue::Bool(b)) => Ok(ResultValue::Bool(a != b)),
+                    (ResultValue::String(a), ResultValue::String(b)) => Ok(ResultValue::Bool(a != b)),
+                    (ResultValue::Char(a), ResultValue::Char(b)) => Ok(ResultValue::Bool(a != b)),
+                    (ResultValue::Symbol(a), ResultValue::Symbol(b)) => Ok(ResultValue::Bool(a != b)),
+                    // nil only equals itself; it's never an error to compare against it
+                    (ResultValue::Nil, ResultValue::Nil) => Ok(ResultValue::Bool(false)),
+                    (ResultValue::Nil, _) | (_, ResultValue::Nil) => Ok(ResultValue::Bool(true)),
+                    _ => Err("Invalid arguments".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "<".to_string(),
+            ResultValue::VarFunc(|args| compare_chain(&args, |order| order.is_lt())),
+        );
+        builtins.insert(
+            ">".to_string(),
+            ResultValue::VarFunc(|args| compare_chain(&args, |order| order.is_gt())),
+        );
+        builtins.insert(
+            "geq".to_string(),
+            ResultValue::VarFunc(|args| compare_chain(&args, |order| order.is_ge())),
+        );
+        builtins.insert(
+            "leq".to_string(),
+            ResultValue::VarFunc(|args| compare_chain(&args, |order| order.is_le())),
+        );
+        builtins.insert(
+            "print".to_string(),
+            ResultValue::VarFunc(|args| {
+                // Space-separated and without a newline, so several prints can build up one line
+                let text: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                print!("{}", text.join(" "));
+                io::stdout().flush().map_err(|e| e.to_string())?;
+                Ok(ResultValue::Nil)
+            }),
+        );
+        builtins.insert(
+            "println".to_string(),
+            ResultValue::VarFunc(|args| {
+                let text: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                println!("{}", text.join(" "));
+                Ok(ResultValue::Nil)
+            }),
+        );
+        builtins.insert(
+            "write-string".to_string(),
+            ResultValue::Func("write-string".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                // The quoted, escaped form the result printer uses
+                print!("{}", Written(&args[0]));
+                io::stdout().flush().map_err(|e| e.to_string())?;
+                Ok(ResultValue::Nil)
+            }),
+        );
+        builtins.insert(
+            "display".to_string(),
+            ResultValue::Func("display".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                // Display leaves strings unquoted, so they come out as their raw text
+                print!("{}", args[0]);
+                io::stdout().flush().map_err(|e| e.to_string())?;
+                Ok(ResultValue::Nil)
+            }),
+        );
+        builtins.insert(
+            "list".to_string(),
+            ResultValue::VarFunc(|args| Ok(ResultValue::List(args))),
+        );
+        builtins.insert(
+            "map".to_string(),
+            ResultValue::EnvFunc("map".into(), 2, |args, env| {
+                if args.len() != 2 {
+                    return Err("Expected exactly 2 arguments".to_string().into());
+                }
+
+                match (args[0].clone(), args[1].clone()) {
+                    (func, ResultValue::List(items)) if func.is_callable() => items
+                        .into_iter()
+                        .map(|item| call_function(func.clone(), vec![item], env))
+                        .collect::<Result<Vec<_>, _>>()
+                        .map(ResultValue::List),
+                    _ => Err("map expects a function and a list".to_string().into()),
+                }
+            }),
+        );
+        builtins.insert(
+            "for-each".to_string(),
+            ResultValue::EnvFunc("for-each".into(), 2, |args, env| {
+                if args.len() != 2 {
+                    return Err("Expected exactly 2 arguments".to_string().into());
+                }
+
+                // Like map, but only for the side effects: the results are dropped
+                match (args[0].clone(), args[1].clone()) {
+                    (func, ResultValue::List(items)) if func.is_callable() => {
+                        for item in items {
+                            call_function(func.clone(), vec![item], env)?;
+                        }
+                        Ok(ResultValue::Nil)
+                    }
+                    _ => Err("for-each expects a function and a list".to_string().into()),
+                }
+            }),
+        );
+        builtins.insert(
+            "sort".to_string(),
+            ResultValue::Func("sort".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                match args[0].clone() {
+                    ResultValue::List(items) => {
+                        let mut numbers = items
+                            .into_iter()
+                            .map(|item| match item {
+                                ResultValue::Number(n) => Ok(n),
+                                _ => Err("sort expects a list of numbers".to_string()),
+                            })
+                            .collect::<Result<Vec<_>, _>>()?;
+                        numbers.sort();
+                        Ok(ResultValue::List(numbers.into_iter().map(ResultValue::Number).collect()))
+                    }
+                    _ => Err("sort expects a list of numbers".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "sort-by".to_string(),
+            ResultValue::EnvFunc("sort-by".into(), 2, |args, env| {
+                if args.len() != 2 {
+                    return Err("Expected exactly 2 arguments".to_string().into());
+                }
+
+                // The comparator says whether its first argument belongs before its second
+                match (args[0].clone(), args[1].clone()) {
+                    (ResultValue::List(items), less) if less.is_callable() => {
+                        let sorted = merge_sort(items, &mut |a, b| {
+                            match call_function(less.clone(), vec![a.clone(), b.clone()], env)? {
+                                ResultValue::Bool(before) => Ok(before),
+                                _ => Err("sort-by comparator must return a boolean".to_string().into()),
+                            }
+                        })?;
+                        Ok(ResultValue::List(sorted))
+                    }
+                    _ => Err("sort-by expects a list and a function".to_string().into()),
+                }
+            }),
+        );
+        builtins.insert(
+            "filter".to_string(),
+            ResultValue::EnvFunc("filter".into(), 2, |args, env| {
+                if args.len() != 2 {
+                    return Err("Expected exactly 2 arguments".to_string().into());
+                }
+
+                match (args[0].clone(), args[1].clone()) {
+                    (func, ResultValue::List(items)) if func.is_callable() => {
+                        let mut kept = Vec::new();
+                        for item in items {
+                            match call_function(func.clone(), vec![item.clone()], env)? {
+                                ResultValue::Bool(true) => kept.push(item),
+                                ResultValue::Bool(false) => {}
+                                _ => return Err("filter predicate must return a boolean".to_string().into()),
+                            }
+                        }
+                        Ok(ResultValue::List(kept))
+                    }
+                    _ => Err("filter expects a function and a list".to_string().into()),
+                }
+            }),
+        );
+        builtins.insert(
+            "fold".to_string(),
+            ResultValue::EnvFunc("fold".into(), 3, |args, env| {
+                if args.len() != 3 {
+                    return Err("Expected exactly 3 arguments".to_string().into());
+                }
+
+                // Left fold: fold(f, seed, (a b)) is f(f(seed, a), b)
+                match (args[0].clone(), args[1].clone(), args[2].clone()) {
+                    (func, seed, ResultValue::List(items)) if func.is_callable() => items
+                        .into_iter()
+                        .try_fold(seed, |acc, item| call_function(func.clone(), vec![acc, item], env)),
+                    _ => Err("fold expects a function, a seed and a list".to_string().into()),
+                }
+            }),
+        );
+        builtins.insert(
+            "apply".to_string(),
+            ResultValue::EnvFunc("apply".into(), 2, |args, env| {
+                if args.len() != 2 {
+                    return Err("Expected exactly 2 arguments".to_string().into());
+                }
+
+                // The list is spread into the argument list, with the usual arity checks
+                match (args[0].clone(), args[1].clone()) {
+                    (func, ResultValue::List(items)) if func.is_callable() => call_function(func, items, env),
+                    _ => Err("apply expects a function and a list".to_string().into()),
+                }
+            }),
+        );
+        builtins.insert(
+            "compose".to_string(),
+            ResultValue::Func("compose".into(), 2, |args| {
+                if args.len() != 2 {
+                    return Err("Expected exactly 2 arguments".to_string());
+                }
+
+                // Arity isn't checked here; a non-unary f or g fails when the composition is called
+                match (args[0].clone(), args[1].clone()) {
+                    (f, g) if f.is_callable() && g.is_callable() => Ok(ResultValue::Composed(Rc::new(f), Rc::new(g))),
+                    _ => Err("compose expects two functions".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "curry".to_string(),
+            ResultValue::VarFunc(|args| {
+                let mut args = args.into_iter();
+                match (args.next(), args.len()) {
+                    (Some(f), 1..) if f.is_callable() => Ok(ResultValue::Curried(Rc::new(f), args.collect())),
+                    (Some(_), 1..) => Err("curry expects a function".to_string()),
+                    _ => Err("Expected at least 2 arguments".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "memoize".to_string(),
+            ResultValue::Func("memoize".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                match args[0].clone() {
+                    f if f.is_callable() => Ok(ResultValue::Memoized(Box::new(f), Rc::new(RefCell::new(HashMap::new())))),
+                    _ => Err("memoize expects a function".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "eval".to_string(),
+            ResultValue::EnvFunc("eval".into(), 1, |args, env| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string().into());
+                }
+
+                // Only quoted code is evaluable: a symbol is looked up, a list is applied
+                match args[0].clone() {
+                    data @ (ResultValue::Symbol(_) | ResultValue::List(_)) => eval_expr(unquote_value(data)?, env),
+                    _ => Err("eval expects a quoted symbol or list".to_string().into()),
+                }
+            }),
+        );
+        builtins.insert(
+            "cons".to_string(),
+            ResultValue::Func("cons".into(), 2, |args| {
+                if args.len() != 2 {
+                    return Err("Expected exactly 2 arguments".to_string());
+                }
+
+                // Consing onto a list gives a longer list; onto anything else, an improper pair
+                match (args[0].clone(), args[1].clone()) {
+                    (head, ResultValue::List(mut tail)) => {
+                        tail.insert(0, head);
+                        Ok(ResultValue::List(tail))
+                    }
+                    (head, tail) => Ok(ResultValue::Pair(Box::new(head), Box::new(tail))),
+                }
+            }),
+        );
+        builtins.insert(
+            "pair?".to_string(),
+            ResultValue::Func("pair?".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                // As in Scheme, a non-empty list is a pair too
+                Ok(ResultValue::Bool(match &args[0] {
+                    ResultValue::Pair(_, _) => true,
+                    ResultValue::List(items) => !items.is_empty(),
+                    _ => false,
+                }))
+            }),
+        );
+        builtins.insert(
+            "car".to_string(),
+            ResultValue::Func("car".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                match args[0].clone() {
+                    ResultValue::List(items) => match items.into_iter().next() {
+                        Some(head) => Ok(head),
+                        None => Err("car of empty list".to_string()),
+                    },
+                    ResultValue::Pair(head, _) => Ok(*head),
+                    _ => Err("Invalid argument".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "cdr".to_string(),
+            ResultValue::Func("cdr".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                match args[0].clone() {
+                    ResultValue::List(items) => {
+                        if items.is_empty() {
+                            Err("cdr of empty list".to_string())
+                        } else {
+                            Ok(ResultValue::List(items[1..].to_vec()))
+                        }
+                    }
+                    ResultValue::Pair(_, tail) => Ok(*tail),
+                    _ => Err("Invalid argument".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "length".to_string(),
+            ResultValue::Func("length".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                // Strings are measured in Unicode scalar values, not bytes
+                match args[0].clone() {
+                    ResultValue::List(items) => Ok(ResultValue::Number(items.len() as i64)),
+                    ResultValue::String(s) => Ok(ResultValue::Number(s.chars().count() as i64)),
+                    _ => Err("length expects a list or string".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "nth".to_string(),
+            ResultValue::Func("nth".into(), 2, |args| {
+                if args.len() != 2 {
+                    return Err("Expected exactly 2 arguments".to_string());
+                }
+
+                match (args[0].clone(), args[1].clone()) {
+                    (ResultValue::List(items), ResultValue::Number(index)) => usize::try_from(index)
+                        .ok()
+                        .and_then(|index| items.into_iter().nth(index))
+                        .ok_or_else(|| "list index out of range".to_string()),
+                    _ => Err("Invalid arguments".to_string()),
+                }
+            }),
+        );
+        // list-ref is the Scheme name for the same lookup
+        builtins.insert("list-ref".to_string(), builtins["nth"].clone());
+        builtins.insert(
+            "take".to_string(),
+            ResultValue::Func("take".into(), 2, |args| {
+                if args.len() != 2 {
+                    return Err("Expected exactly 2 arguments".to_string());
+                }
+
+                // Taking more than the list holds gives the whole list rather than an error
+                match (args[0].clone(), args[1].clone()) {
+                    (ResultValue::List(_), ResultValue::Number(n)) if n < 0 => Err("take count must not be negative".to_string()),
+                    (ResultValue::List(items), ResultValue::Number(n)) => {
+                        let n = usize::try_from(n).unwrap_or(usize::MAX);
+                        Ok(ResultValue::List(items.into_iter().take(n).collect()))
+                    }
+                    _ => Err("Invalid arguments".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "drop".to_string(),
+            ResultValue::Func("drop".into(), 2, |args| {
+                if args.len() != 2 {
+                    return Err("Expected exactly 2 arguments".to_string());
+                }
+
+                // Dropping more than the list holds gives the empty list rather than an error
+                match (args[0].clone(), args[1].clone()) {
+                    (ResultValue::List(_), ResultValue::Number(n)) if n < 0 => Err("drop count must not be negative".to_string()),
+                    (ResultValue::List(items), ResultValue::Number(n)) => {
+                        let n = usize::try_from(n).unwrap_or(usize::MAX);
+                        Ok(ResultValue::List(items.into_iter().skip(n).collect()))
+                    }
+                    _ => Err("Invalid arguments".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "zip".to_string(),
+            ResultValue::Func("zip".into(), 2, |args| {
+                if args.len() != 2 {
+                    return Err("Expected exactly 2 arguments".to_string());
+                }
+
+                // Stops at the end of the shorter list; the longer one's extra elements are dropped
+                match (args[0].clone(), args[1].clone()) {
+                    (ResultValue::List(a), ResultValue::List(b)) => Ok(ResultValue::List(
+                        a.into_iter().zip(b).map(|(a, b)| ResultValue::List(vec![a, b])).collect(),
+                    )),
+                    _ => Err("zip expects two lists".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "append".to_string(),
+            ResultValue::VarFunc(|args| {
+                let mut result = Vec::new();
+                for arg in args {
+                    match arg {
+                        ResultValue::List(items) => result.extend(items),
+                        _ => return Err("append expects lists".to_string()),
+                    }
+                }
+                Ok(ResultValue::List(result))
+            }),
+        );
+        builtins.insert(
+            "reverse".to_string(),
+            ResultValue::Func("reverse".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                match args[0].clone() {
+                    ResultValue::List(mut items) => {
+                        items.reverse();
+                        Ok(ResultValue::List(items))
+                    }
+                    _ => Err("Invalid argument".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "make-map".to_string(),
+            ResultValue::Func("make-map".into(), 0, |args| {
+                if !args.is_empty() {
+                    return Err("Expected no arguments".to_string());
+                }
+
+                Ok(ResultValue::Map(HashMap::new()))
+            }),
+        );
+        builtins.insert(
+            "map-get".to_string(),
+            ResultValue::Func("map-get".into(), 2, |args| {
+                if args.len() != 2 {
+                    return Err("Expected exactly 2 arguments".to_string());
+                }
+
+                // A missing key is nil rather than an error
+                match (args[0].clone(), args[1].clone()) {
+                    (ResultValue::Map(entries), ResultValue::String(key)) => {
+                        Ok(entries.get(&key).cloned().unwrap_or(ResultValue::Nil))
+                    }
+                    _ => Err("map-get expects a map and a string key".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "map-set".to_string(),
+            ResultValue::Func("map-set".into(), 3, |args| {
+                if args.len() != 3 {
+                    return Err("Expected exactly 3 arguments".to_string());
+                }
+
+                // Returns an updated copy; the map passed in is left as it was
+                match (args[0].clone(), args[1].clone(), args[2].clone()) {
+                    (ResultValue::Map(mut entries), ResultValue::String(key), value) => {
+                        entries.insert(key, value);
+                        Ok(ResultValue::Map(entries))
+                    }
+                    _ => Err("map-set expects a map, a string key and a value".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "map-keys".to_string(),
+            ResultValue::Func("map-keys".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                match args[0].clone() {
+                    ResultValue::Map(entries) => {
+                        let mut keys: Vec<String> = entries.into_keys().collect();
+                        keys.sort();
+                        Ok(ResultValue::List(keys.into_iter().map(ResultValue::String).collect()))
+                    }
+                    _ => Err("map-keys expects a map".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "range".to_string(),
+            ResultValue::VarFunc(|args| {
+                let (start, end, step) = match args.as_slice() {
+                    [ResultValue::Number(start), ResultValue::Number(end)] => (*start, *end, 1),
+                    [ResultValue::Number(start), ResultValue::Number(end), ResultValue::Number(step)] => (*start, *end, *step),
+                    [_, _] | [_, _, _] => return Err("Invalid arguments".to_string()),
+                    _ => return Err("Expected 2 or 3 arguments".to_string()),
+                };
+                if step == 0 {
+                    return Err("range step must not be zero".to_string());
+                }
+
+                // The end is exclusive in the direction of the step, so range(5, 0, -1) is (5 4 3 2 1)
+                let mut items = Vec::new();
+                let mut n = Some(start);
+                while let Some(current) = n.filter(|&n| if step > 0 { n < end } else { n > end }) {
+                    items.push(ResultValue::Number(current));
+                    n = current.checked_add(step);
+                }
+                Ok(ResultValue::List(items))
+            }),
+        );
+        builtins.insert(
+            "string-append".to_string(),
+            ResultValue::VarFunc(|args| {
+                let mut result = String::new();
+                for arg in args {
+                    match arg {
+                        ResultValue::String(s) => result.push_str(&s),
+                        _ => return Err("Invalid arguments".to_string()),
+                    }
+                }
+                Ok(ResultValue::String(result))
+            }),
+        );
+        // Case mapping and trimming follow Unicode rules, e.g. "straße" upcases to "STRASSE"
+        builtins.insert(
+            "string-upcase".to_string(),
+            ResultValue::Func("string-upcase".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                match args[0].clone() {
+                    ResultValue::String(s) => Ok(ResultValue::String(s.to_uppercase())),
+                    _ => Err("Expected a string".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "string-downcase".to_string(),
+            ResultValue::Func("string-downcase".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                match args[0].clone() {
+                    ResultValue::String(s) => Ok(ResultValue::String(s.to_lowercase())),
+                    _ => Err("Expected a string".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "string-trim".to_string(),
+            ResultValue::Func("string-trim".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                match args[0].clone() {
+                    ResultValue::String(s) => Ok(ResultValue::String(s.trim().to_string())),
+                    _ => Err("Expected a string".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "string-contains".to_string(),
+            ResultValue::Func("string-contains".into(), 2, |args| {
+                if args.len() != 2 {
+                    return Err("Expected exactly 2 arguments".to_string());
+                }
+
+                match (args[0].clone(), args[1].clone()) {
+                    (ResultValue::String(s), ResultValue::String(needle)) => Ok(ResultValue::Bool(s.contains(needle.as_str()))),
+                    _ => Err("Expected a string".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "starts-with".to_string(),
+            ResultValue::Func("starts-with".into(), 2, |args| {
+                if args.len() != 2 {
+                    return Err("Expected exactly 2 arguments".to_string());
+                }
+
+                match (args[0].clone(), args[1].clone()) {
+                    (ResultValue::String(s), ResultValue::String(needle)) => Ok(ResultValue::Bool(s.starts_with(needle.as_str()))),
+                    _ => Err("Expected a string".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "ends-with".to_string(),
+            ResultValue::Func("ends-with".into(), 2, |args| {
+                if args.len() != 2 {
+                    return Err("Expected exactly 2 arguments".to_string());
+                }
+
+                match (args[0].clone(), args[1].clone()) {
+                    (ResultValue::String(s), ResultValue::String(needle)) => Ok(ResultValue::Bool(s.ends_with(needle.as_str()))),
+                    _ => Err("Expected a string".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "format".to_string(),
+            ResultValue::VarFunc(|args| {
+                let mut args = args.into_iter();
+                let template = match args.next() {
+                    Some(ResultValue::String(template)) => template,
+                    Some(_) => return Err("format expects a template string".to_string()),
+                    None => return Err("Expected at least 1 argument".to_string()),
+                };
+
+                // Each {} takes the next value in its display form; {{ and }} are literal braces
+                let mut result = String::new();
+                let mut chars = template.chars().peekable();
+                while let Some(c) = chars.next() {
+                    match (c, chars.peek()) {
+                        ('{', Some('{')) | ('}', Some('}')) => {
+                            chars.next();
+                            result.push(c);
+                        }
+                        ('{', Some('}')) => {
+                            chars.next();
+                            match args.next() {
+                                Some(value) => result.push_str(&value.to_string()),
+                                None => return Err("Too few arguments for format string".to_string()),
+                            }
+                        }
+                        ('{' | '}', _) => return Err("Unmatched brace in format string".to_string()),
+                        _ => result.push(c),
+                    }
+                }
+                if args.next().is_some() {
+                    return Err("Too many arguments for format string".to_string());
+                }
+                Ok(ResultValue::String(result))
+            }),
+        );
+        builtins.insert(
+            "string-split".to_string(),
+            ResultValue::Func("string-split".into(), 2, |args| {
+                if args.len() != 2 {
+                    return Err("Expected exactly 2 arguments".to_string());
+                }
+
+                // Empty fields are kept ("a,,b" gives three parts); an empty separator is rejected
+                // rather than guessing at splitting into characters
+                match (args[0].clone(), args[1].clone()) {
+                    (ResultValue::String(_), ResultValue::String(sep)) if sep.is_empty() => {
+                        Err("empty separator".to_string())
+                    }
+                    (ResultValue::String(s), ResultValue::String(sep)) => Ok(ResultValue::List(
+                        s.split(sep.as_str()).map(|part| ResultValue::String(part.to_string())).collect(),
+                    )),
+                    _ => Err("Expected a string".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "string->number".to_string(),
+            ResultValue::Func("string->number".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                match args[0].clone() {
+                    ResultValue::String(s) => parse_decimal(s.trim()).ok_or_else(|| format!("Not a number: {}", s)),
+                    _ => Err("Invalid argument".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "number->string".to_string(),
+            ResultValue::Func("number->string".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                match args[0].clone() {
+                    value @ (ResultValue::Number(_) | ResultValue::Float(_)) => Ok(ResultValue::String(value.to_string())),
+                    _ => Err("Invalid argument".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "substring".to_string(),
+            ResultValue::Func("substring".into(), 3, |args| {
+                if args.len() != 3 {
+                    return Err("Expected exactly 3 arguments".to_string());
+                }
+
+                // Indices count characters, not bytes; the end index is exclusive
+                match (args[0].clone(), args[1].clone(), args[2].clone()) {
+                    (ResultValue::String(s), ResultValue::Number(start), ResultValue::Number(end)) => {
+                        let length = s.chars().count() as i64;
+                        if start < 0 || end < start || end > length {
+                            return Err("substring index out of range".to_string());
+                        }
+                        let slice = s.chars().skip(start as usize).take((end - start) as usize).collect();
+                        Ok(ResultValue::String(slice))
+                    }
+                    _ => Err("Invalid arguments".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "assert".to_string(),
+            ResultValue::VarFunc(|args| match args.as_slice() {
+                [ResultValue::Bool(true)] | [ResultValue::Bool(true), ResultValue::String(_)] => Ok(ResultValue::Nil),
+                [ResultValue::Bool(false)] => Err("Assertion failed".to_string()),
+                [ResultValue::Bool(false), ResultValue::String(message)] => Err(message.clone()),
+                [_] | [_, _] => Err("assert expects a boolean and an optional message".to_string()),
+                _ => Err("Expected 1 or 2 arguments".to_string()),
+            }),
+        );
+        builtins.insert(
+            "error".to_string(),
+            ResultValue::Func("error".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                match args[0].clone() {
+                    ResultValue::String(message) => Err(message),
+                    _ => Err("error expects a string".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "read-line".to_string(),
+            ResultValue::Func("read-line".into(), 0, |args| {
+                if !args.is_empty() {
+                    return Err("Expected no arguments".to_string());
+                }
+
+                Ok(read_stdin_line()?.map_or(ResultValue::Nil, ResultValue::String))
+            }),
+        );
+        builtins.insert(
+            "read-number".to_string(),
+            ResultValue::Func("read-number".into(), 0, |args| {
+                if !args.is_empty() {
+                    return Err("Expected no arguments".to_string());
+                }
+
+                let line = read_stdin_line()?.ok_or_else(|| "read-number at end of input".to_string())?;
+                parse_decimal(line.trim()).ok_or_else(|| format!("Not a number: {}", line))
+            }),
+        );
+        builtins.insert(
+            "gensym".to_string(),
+            ResultValue::VarFunc(|args| {
+                let prefix = match args.as_slice() {
+                    [] => "g".to_string(),
+                    [ResultValue::String(prefix)] => prefix.clone(),
+                    [_] => return Err("gensym prefix must be a string".to_string()),
+                    _ => return Err("Expected at most 1 argument".to_string()),
+                };
+                let id = GENSYM_COUNTER.fetch_add(1, Ordering::Relaxed);
+                Ok(ResultValue::Symbol(format!("{}{}", prefix, id)))
+            }),
+        );
+        builtins.insert(
+            "string-ref".to_string(),
+            ResultValue::Func("string-ref".into(), 2, |args| {
+                if args.len() != 2 {
+                    return Err("Expected exactly 2 arguments".to_string());
+                }
+
+                // Like substring, the index counts characters rather than bytes
+                match (args[0].clone(), args[1].clone()) {
+                    (ResultValue::String(s), ResultValue::Number(index)) => usize::try_from(index)
+                        .ok()
+                        .and_then(|index| s.chars().nth(index))
+                        .map(ResultValue::Char)
+                        .ok_or_else(|| "string-ref index out of range".to_string()),
+                    _ => Err("Invalid arguments".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "string->list".to_string(),
+            ResultValue::Func("string->list".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                match args[0].clone() {
+                    ResultValue::String(s) => Ok(ResultValue::List(s.chars().map(ResultValue::Char).collect())),
+                    _ => Err("Expected a string".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "list->string".to_string(),
+            ResultValue::Func("list->string".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                // Strings in the list are joined along with chars
+                match args[0].clone() {
+                    ResultValue::List(items) => {
+                        let mut result = String::new();
+                        for item in items {
+                            match item {
+                                ResultValue::Char(c) => result.push(c),
+                                ResultValue::String(s) => result.push_str(&s),
+                                _ => return Err("list->string expects chars or strings".to_string()),
+                            }
+                        }
+                        Ok(ResultValue::String(result))
+                    }
+                    _ => Err("Invalid argument".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "char->number".to_string(),
+            ResultValue::Func("char->number".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                match args[0].clone() {
+                    ResultValue::Char(c) => Ok(ResultValue::Number(c as i64)),
+                    _ => Err("Expected a char".to_string()),
+                }
+            }),
+        );
+        builtins.insert(
+            "number->char".to_string(),
+            ResultValue::Func("number->char".into(), 1, |args| {
+                if args.len() != 1 {
+                    return Err("Expected exactly 1 argument".to_string());
+                }
+
+                match args[0].clone() {
+                    ResultValue::Number(n) => u32::try_from(n)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .map(ResultValue::Char)
+                        .ok_or_else(|| format!("Invalid code point: {}", n)),
+                    _ => Err("Invalid argument".to_string()),
+                }
+            }),
+        );
+        
+
+        let mut env = Self { vars: HashMap::new(), builtins, parent: None };
+        for &(name, arity, func) in extra_builtins {
+            env.register_builtin(name, arity, func);
+        }
+        env
+    }
+
+    // Adds (or replaces) a fixed-arity builtin; like all builtins it lives in the root scope
+    pub fn register_builtin(&mut self, name: &str, arity: usize, func: Builtin) {
+        match self.parent.as_ref() {
+            Some(parent) => parent.borrow_mut().register_builtin(name, arity, func),
+            None => {
+                self.builtins.insert(name.to_string(), ResultValue::Func(name.into(), arity, func));
+            }
+        }
+    }
+
+    // Creates an empty child scope that shares (rather than copies) its parent
+    fn with_parent(parent: SharedEnv) -> SharedEnv {
+        Rc::new(RefCell::new(Self {
+            vars: HashMap::new(),
+            builtins: HashMap::new(),
+            parent: Some(parent),
+        }))
+    }
+
+    // Collapses the scopes from `env` out to and including `frame` into one fresh scope holding the
+    // same bindings, so a chain of dynamically scoped tail calls doesn't keep growing the scope chain
+    fn flatten(env: &SharedEnv, frame: &SharedEnv) -> SharedEnv {
+        let mut vars = HashMap::new();
+        let mut parent = None;
+        let mut current = Some(env.clone());
+        while let Some(scope) = current {
+            let scope_ref = scope.borrow();
+            for (name, value) in &scope_ref.vars {
+                vars.entry(name.clone()).or_insert_with(|| value.clone());
+            }
+            if Rc::ptr_eq(&scope, frame) {
+                parent = scope_ref.parent.clone();
+                break;
+            }
+            current = scope_ref.parent.clone();
+        }
+        Rc::new(RefCell::new(Self {
+            vars,
+            builtins: HashMap::new(),
+            parent,
+        }))
+    }
+
+    // Copies the variables of this scope and its parents (see EnvSnapshot for what that costs)
+    pub fn snapshot(&self) -> EnvSnapshot {
+        EnvSnapshot {
+            vars: self.vars.clone(),
+            parent: self.parent.as_ref().map(|parent| Box::new(parent.borrow().snapshot())),
+        }
+    }
+
+    // Puts back the variables of this scope and its parents as they were at the snapshot
+    pub fn restore(&mut self, snapshot: EnvSnapshot) {
+        self.vars = snapshot.vars;
+        if let (Some(parent), Some(snapshot)) = (&self.parent, snapshot.parent) {
+            parent.borrow_mut().restore(*snapshot);
+        }
+    }
+
+    // Every variable visible from this scope as `name = value` lines sorted by name, so the output
+    // doesn't depend on HashMap order; a shadowed outer binding is left out. Values are in their
+    // written form, so a string holding a newline still takes up one line.
+    pub fn dump(&self) -> String {
+        let mut bindings = BTreeMap::new();
+        self.collect_bindings(&mut bindings);
+        bindings
+            .into_iter()
+            .map(|(name, value)| format!("{} = {}", name, Written(&value)))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    // Inner scopes are visited first, so their bindings win over outer ones of the same name
+    fn collect_bindings(&self, bindings: &mut BTreeMap<String, ResultValue>) {
+        for (name, value) in &self.vars {
+            bindings.entry(name.clone()).or_insert_with(|| value.clone());
+        }
+        if let Some(parent) = &self.parent {
+            parent.borrow().collect_bindings(bindings);
+        }
+    }
+
+    fn get_vars(&self, name: &str) -> Option<ResultValue> {
+        match self.vars.get(name) {
+            Some(value) => Some(value.clone()),
+            None => self.parent.as_ref().and_then(|parent| parent.borrow().get_vars(name)),
+        }
+    }
+
+    // Resolves a name as a variable first, falling back to the builtins
+    fn lookup(&self, name: &str) -> Option<ResultValue> {
+        self.get_vars(name).or_else(|| self.get_builtin(name))
+    }
+
+    fn get_builtin(&self, name: &str) -> Option<ResultValue> {
+        match self.parent.as_ref() {
+            Some(parent) => parent.borrow().get_builtin(name),
+            None => self.builtins.get(name).cloned(),
+        }
+    }
+
+    pub fn insert_vars(&mut self, name: String, value: ResultValue) {
+        self.vars.insert(name, value);
+    }
+
+    fn update_vars(&mut self, name: &str, value: ResultValue) -> Result<(), String> {
+        if self.set_var(name, value) {
+            Ok(())
+        } else {
+            Err("Unbound identifier".to_string())
+        }
+    }
+
+    // Updates the nearest existing binding of `name`, walking out through the parents; false if there is none
+    fn set_var(&mut self, name: &str, value: ResultValue) -> bool {
+        if let Some(slot) = self.vars.get_mut(name) {
+            *slot = value;
+            true
+        } else if let Some(parent) = self.parent.as_ref() {
+            parent.borrow_mut().set_var(name, value)
+        } else {
+            false
+        }
+    }
+}
+
+// Programs spell these constants as plain identifiers, e.g. `(true => ...)` in a cond. They are
+// resolved before any variable, and can't be defined or assigned, so no program can rebind them.
+fn constant(name: &str) -> Option<ResultValue> {
+    match name {
+        "true" => Some(ResultValue::Bool(true)),
+        "false" => Some(ResultValue::Bool(false)),
+        "nil" => Some(ResultValue::Nil),
+        _ => None,
+    }
+}
+
+fn check_not_constant(name: &str) -> Result<(), String> {
+    match constant(name) {
+        Some(_) => Err(format!("Cannot rebind constant: {}", name)),
+        None => Ok(()),
+    }
+}
+
+// True when every adjacent pair of numbers satisfies the relation, so <(1, 2, 3) checks 1 < 2 and 2 < 3
+fn compare_chain(args: &[ResultValue], holds: fn(std::cmp::Ordering) -> bool) -> Result<ResultValue, String> {
+    if args.is_empty() {
+        return Err("Expected at least 1 argument".to_string());
+    }
+    if !args.iter().all(|arg| arg.as_float().is_some()) {
+        return Err("Invalid arguments".to_string());
+    }
+    Ok(ResultValue::Bool(
+        args.windows(2).all(|pair| compare_numbers(&pair[0], &pair[1]).is_some_and(holds)),
+    ))
+}
+
+// Orders two numbers of any kind: exactly unless one is a Float, which compares as floats do (a NaN
+// is unordered, so no relation holds for it). None when either isn't a number.
+fn compare_numbers(a: &ResultValue, b: &ResultValue) -> Option<std::cmp::Ordering> {
+    match (a.as_ratio(), b.as_ratio()) {
+        // Denominators are positive, so cross-multiplying keeps the order
+        (Some((an, ad)), Some((bn, bd))) => Some((an * bd).cmp(&(bn * ad))),
+        _ => a.as_float()?.partial_cmp(&b.as_float()?),
+    }
+}
+
+// Normalizes a fraction to lowest terms with a positive denominator; a whole result is a Number
+fn rational(numerator: i128, denominator: i128) -> Result<ResultValue, String> {
+    if denominator == 0 {
+        return Err("Zero denominator".to_string());
+    }
+    let sign = denominator.signum();
+    let (mut a, mut b) = (numerator.unsigned_abs(), denominator.unsigned_abs());
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    let divisor = a.max(1) as i128;
+    let (numerator, denominator) = (sign * numerator / divisor, sign * denominator / divisor);
+    match (i64::try_from(numerator), i64::try_from(denominator)) {
+        (Ok(n), Ok(1)) => Ok(ResultValue::Number(n)),
+        (Ok(n), Ok(d)) => Ok(ResultValue::Rational(n, d)),
+        _ => Err("Integer overflow".to_string()),
+    }
+}
+
+// Both operands as exact fractions, when at least one is a Rational (two Numbers keep their own rules)
+fn exact_pair(a: &ResultValue, b: &ResultValue) -> Option<((i128, i128), (i128, i128))> {
+    if !matches!(a, ResultValue::Rational(_, _)) && !matches!(b, ResultValue::Rational(_, _)) {
+        return None;
+    }
+    Some((a.as_ratio()?, b.as_ratio()?))
+}
+
+// Stable merge sort with a fallible "a goes before b" test, which (unlike slice sorting) stays well
+// defined when a user comparator isn't a consistent ordering
+fn merge_sort(
+    mut items: Vec<ResultValue>,
+    less: &mut dyn FnMut(&ResultValue, &ResultValue) -> Result<bool, EvalError>,
+) -> Result<Vec<ResultValue>, EvalError> {
+    if items.len() <= 1 {
+        return Ok(items);
+    }
+    let right = items.split_off(items.len() / 2);
+    let mut left = merge_sort(items, less)?.into_iter().peekable();
+    let mut right = merge_sort(right, less)?.into_iter().peekable();
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    while let (Some(a), Some(b)) = (left.peek(), right.peek()) {
+        // Equal elements keep their order: the right one only goes first when strictly before
+        let next = if less(b, a)? { right.next() } else { left.next() };
+        merged.extend(next);
+    }
+    merged.extend(left);
+    merged.extend(right);
+    Ok(merged)
+}
+
+// Greatest common divisor of the absolute values, by the Euclidean algorithm; gcd(0, 0) is 0
+fn gcd(a: i64, b: i64) -> u64 {
+    let (mut a, mut b) = (a.unsigned_abs(), b.unsigned_abs());
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+pub fn eval_expr(expr: Expr, env: &SharedEnv) -> Result<ResultValue, EvalError> {
+    let evaluate = |expr| {
+        let mut frames = Vec::new();
+        eval_tail(expr, env.clone(), &mut frames)
+            .map_err(|e| frames.into_iter().rev().fold(e, |e, frame| e.within(frame)))
+    };
+    // Every nested evaluation takes Rust stack, so runaway recursion is cut off with an error first
+    let eval_depth = EVAL_DEPTH.get();
+    if eval_depth >= MAX_DEPTH.load(Ordering::Relaxed) {
+        return Err("Maximum recursion depth exceeded".to_string().into());
+    }
+    EVAL_DEPTH.set(eval_depth + 1);
+    let result = if !TRACE.load(Ordering::Relaxed) {
+        evaluate(expr)
+    } else {
+        // Tracing: log the expression on the way in and its value on the way out, indented by depth
+        let depth = TRACE_DEPTH.get();
+        TRACE_DEPTH.set(depth + 1);
+        let indent = "  ".repeat(depth);
+        // Forms that continue in one of their subexpressions are marked, showing where evaluation loops
+        let marker = if is_tail_position(&expr) { " [tail]" } else { "" };
+        eprintln!("{}-> {:?}{}", indent, expr, marker);
+        let result = evaluate(expr);
+        TRACE_DEPTH.set(depth);
+        match &result {
+            Ok(value) => eprintln!("{}<- {}", indent, value),
+            Err(e) => eprintln!("{}<- error: {}", indent, e.message),
+        }
+        result
+    };
+    EVAL_DEPTH.set(eval_depth);
+    result
+}
+
+// Whether `expr` is a tail-position form: one that finishes by evaluating one of its own
+// subexpressions (an If branch, the chosen Cond clause, the last expression of a Block and so on) in
+// tail position, which eval_tail does by looping instead of recursing. An Application isn't one: the
+// lambda body a call continues in belongs to the lambda, not to the call, so eval_tail checks for
+// calls separately.
+fn is_tail_position(expr: &Expr) -> bool {
+    match expr {
+        Expr::If(_, _, _)
+        | Expr::Cond(_)
+        | Expr::Case(_, _)
+        | Expr::Block(_)
+        | Expr::Begin(_)
+        | Expr::Scope(_)
+        | Expr::Let(_, _, _)
+        | Expr::LetStar(_, _)
+        | Expr::LetMany(_, _)
+        | Expr::LetRec(_, _)
+        | Expr::IfLet(_, _, _, _)
+        | Expr::When(_, _)
+        | Expr::Unless(_, _)
+        | Expr::Try(_, _) => true,
+        Expr::Application(_)
+        | Expr::Identifier(_)
+        | Expr::Clause(_)
+        | Expr::Number(_)
+        | Expr::NumberStr(_)
+        | Expr::Float(_)
+        | Expr::Bool(_)
+        | Expr::String(_)
+        | Expr::Parameters(_)
+        | Expr::Lambda(_)
+        | Expr::Define(_, _)
+        | Expr::Assignment(_, _)
+        | Expr::And(_)
+        | Expr::Or(_)
+        | Expr::Set(_, _)
+        | Expr::While(_, _)
+        | Expr::Quote(_)
+        | Expr::Defined(_)
+        | Expr::Do(_, _, _, _)
+        | Expr::Time(_)
+        | Expr::DumpEnv => false,
+    }
+}
+
+// Evaluates `expr`, looping rather than recursing for expressions in tail position (lambda bodies,
+// and the subexpressions that the forms listed in is_tail_position finish with, such as If
+// branches, the chosen Cond clause and the last expression of a Block) so that tail-recursive
+// programs run in constant Rust stack. Error path frames for those tail positions are collected in
+// `frames` since there is no Rust call to attach them on the way out.
+fn eval_tail(mut expr: Expr, mut env: SharedEnv, frames: &mut Vec<String>) -> Result<ResultValue, EvalError> {
+    // The scope made by the latest tail call to a lambda, and the frame count before the first one
+    let mut tail_call: Option<(SharedEnv, usize)> = None;
+
+    loop {
+        // // backtrace for debugging
+        // println!("{:?}", expr);
+
+        // Only calls and the forms is_tail_position lists may go round the loop again
+        let may_loop = matches!(expr, Expr::Application(_)) || is_tail_position(&expr);
+        match expr {
+            Expr::Number(n) => return Ok(ResultValue::Number(n)),
+            Expr::NumberStr(literal) => return Ok(ResultValue::Number(parse_number_literal(&literal)?)),
+            Expr::Float(x) => return Ok(ResultValue::Float(x)),
+            Expr::Bool(b) => return Ok(ResultValue::Bool(b)),
+            Expr::String(s) => return Ok(ResultValue::String(s)),
+
+            Expr::Application(mut args) => {
+                // A named head resolves directly to a variable or builtin (user bindings win). An
+                // anonymous lambda, e.g. one passed in as an argument, is named after the parameter
+                // it's called through.
+                let func = match args.remove(0) {
+                    Expr::Identifier(name) => match env.borrow().lookup(&name) {
+                        Some(func) => func.named(&name),
+                        None => return Err(format!("Unbound function: {}", name).into()),
+                    },
+                    head => eval_expr(head, &env).map_err(|e| e.within("Application[0]"))?,
+                };
+                let arg_values = eval_arguments(args, &env)?;
+
+                match enter_call(func, arg_values, &env, &mut tail_call, frames)? {
+                    TailCall::Body(body, scope) => {
+                        expr = body;
+                        env = scope;
+                    }
+                    TailCall::Done(value) => return Ok(value),
+                }
+            }
+
+            // Builtins can be passed around as values too, e.g. map(abs, ...)
+            Expr::Identifier(value) => {
+                return match constant(&value).or_else(|| env.borrow().lookup(&value)) {
+                    Some(val) => Ok(val),
+                    None => Err(format!("Unbound variable: {}", value).into()),
+                }
+            }
+
+            // `defined?` looks the name up without evaluating it, so an unbound name is just false
+            Expr::Defined(name) => {
+                return match *name {
+                    Expr::Identifier(name) => Ok(ResultValue::Bool(constant(&name).is_some() || env.borrow().lookup(&name).is_some())),
+                    _ => Err("defined? expects an identifier".to_string().into()),
+                };
+            }
+
+            Expr::Block(mut exprs) => {
+                let last = match exprs.pop() {
+                    Some(last) => last,
+                    None => return Ok(ResultValue::Nil),
+                };
+                let last_index = exprs.len();
+                for (index, expr) in exprs.into_iter().enumerate() {
+                    eval_expr(expr, &env).map_err(|e| e.within(format!("Block[{}]", index)))?;
+                }
+                frames.push(format!("Block[{}]", last_index));
+                expr = last;
+            }
+
+            // A Block in its own child scope, so defines inside it are dropped once it's done
+            Expr::Scope(exprs) => {
+                expr = Expr::Block(exprs);
+                env = Env::with_parent(env.clone());
+            }
+
+            // Like Block, but an empty sequence is an error rather than a silent nil
+            Expr::Begin(mut exprs) => {
+                let last = match exprs.pop() {
+                    Some(last) => last,
+                    None => return Err("empty begin".to_string().into()),
+                };
+                let last_index = exprs.len();
+                for (index, expr) in exprs.into_iter().enumerate() {
+                    eval_expr(expr, &env).map_err(|e| e.within(format!("Begin[{}]", index)))?;
+                }
+                frames.push(format!("Begin[{}]", last_index));
+                expr = last;
+            }
+
+            Expr::Cond(clauses) => {
+                let last = clauses.len().saturating_sub(1);
+                let mut chosen = None;
+                let mut arrow_call = None;
+                for (index, clause) in clauses.into_iter().enumerate() {
+                    let frame = format!("Cond clause {}", index + 1);
+                    match clause {
+                        Expr::Clause(mut clause) => {
+                            if clause.is_empty() {
+                                return Err("Each clause must have at least 1 expression".to_string().into());
+                            }
+                            // (test => func): the test value is passed on to func, called in tail position
+                            if clause.len() == 3 && matches!(&clause[1], Expr::Identifier(arrow) if arrow == "=>") {
+                                let func = clause.remove(2);
+                                let value = eval_expr(clause.remove(0), &env).map_err(|e| e.within(frame.clone()))?;
+                                if !value.is_truthy() {
+                                    continue;
+                                }
+                                let func = eval_expr(func, &env).map_err(|e| e.within(frame.clone()))?;
+                                frames.push(frame);
+                                arrow_call = Some((func, value));
+                                break;
+                            }
+                            // A clause with several body expressions runs them in sequence, like begin
+                            let test = clause.remove(0);
+                            let body = match clause.len() {
+                                0 => None,
+                                1 => Some(clause.remove(0)),
+                                _ => Some(Expr::Begin(clause)),
+                            };
+                            // An `else` test always matches, but only makes sense as the final clause
+                            if matches!(&test, Expr::Identifier(name) if name == "else") {
+                                if index != last {
+                                    return Err("else must be the final clause".to_string().into());
+                                }
+                                chosen = Some((frame, body.unwrap_or(Expr::Bool(true))));
+                                break;
+                            }
+                            // A clause matches when its test is truthy (everything but false and nil, see
+                            // is_truthy); a test-only clause then yields the test value itself
+                            let value = eval_expr(test, &env).map_err(|e| e.within(frame.clone()))?;
+                            if value.is_truthy() {
+                                match body {
+                                    Some(body) => chosen = Some((frame, body)),
+                                    None => return Ok(value),
+                                }
+                                break;
+                            }
+                        }
+                        _ => return Err("Invalid clause".to_string().into()),
+                    }
+                }
+                match (arrow_call, chosen) {
+                    (Some((func, value)), _) => match enter_call(func, vec![value], &env, &mut tail_call, frames)? {
+                        TailCall::Body(body, scope) => {
+                            expr = body;
+                            env = scope;
+                        }
+                        TailCall::Done(value) => return Ok(value),
+                    },
+                    (None, Some((frame, body))) => {
+                        frames.push(frame);
+                        expr = body;
+                    }
+                    (None, None) => return Err("No true clause".to_string().into()),
+                }
+            }
+
+            // Each clause starts with a constant, or an application-shaped list of alternatives, taken as
+            // quoted data; the first clause with one structurally equal to the key runs its body
+            Expr::Case(key, clauses) => {
+                let key = eval_expr(*key, &env).map_err(|e| e.within("Case key"))?;
+                let last = clauses.len().saturating_sub(1);
+                let mut chosen = None;
+                for (index, clause) in clauses.into_iter().enumerate() {
+                    let mut clause = match clause {
+                        Expr::Clause(clause) if clause.len() >= 2 => clause,
+                        _ => return Err("Each case clause must have constants and a body".to_string().into()),
+                    };
+                    let constants = clause.remove(0);
+                    let body = if clause.len() == 1 { clause.remove(0) } else { Expr::Begin(clause) };
+                    let frame = format!("Case clause {}", index + 1);
+                    let matched = match constants {
+                        Expr::Identifier(name) if name == "else" => {
+                            if index != last {
+                                return Err("else must be the final clause".to_string().into());
+                            }
+                            true
+                        }
+                        Expr::Application(alternatives) => alternatives
+                            .into_iter()
+                            .map(quote_expr)
+                            .collect::<Result<Vec<_>, _>>()?
+                            .iter()
+                            .any(|constant| constant.deep_equal(&key)),
+                        constant => quote_expr(constant)?.deep_equal(&key),
+                    };
+                    if matched {
+                        chosen = Some((frame, body));
+                        break;
+                    }
+                }
+                match chosen {
+                    Some((frame, body)) => {
+                        frames.push(frame);
+                        expr = body;
+                    }
+                    None => return Ok(ResultValue::Nil),
+                }
+            }
+
+            Expr::Quote(quoted) => return Ok(quote_expr(*quoted)?),
+
+            Expr::Clause(_) => return Err("Invalid clause not wrapped in a cond".to_string().into()),
+
+            Expr::Parameters(_) => return Err("Invalid parameters not wrapped in a lambda".to_string().into()),
+
+            Expr::Lambda(mut args) => {
+                if args.len() != 2 {
+                    return Err("Lambda must have exactly 2 expressions".to_string().into());
+                }
+                let params = args.remove(0);
+                let body_expr = args.remove(0);
+                let param_names = if let Expr::Parameters(params) = params {
+                    parameter_names(params)?
+                } else {
+                    return Err("Invalid parameters".to_string().into());
+                };
+                // The scope is captured by reference, so set! inside the lambda (e.g. a counter) is seen by
+                // later calls and by other closures created in the same scope
+                return Ok(ResultValue::Lambda(None, param_names, Rc::new(body_expr), env.clone()));
+            }
+
+            Expr::Let(name, value, body) => {
+                let name = if let Expr::Identifier(name) = *name {
+                    name
+                } else {
+                    return Err("Invalid variable name".to_string().into());
+                };
+                // The value sees the enclosing scope, so `let x = add(x, 1)` reads the outer x
+                let value = eval_expr(*value, &env)?.named(&name);
+
+                // Bind in a child scope so the binding is gone once the body has been evaluated
+                let scope = Env::with_parent(env.clone());
+                scope.borrow_mut().insert_vars(name, value);
+                frames.push("Let body".to_string());
+                expr = *body;
+                env = scope;
+            }
+
+            // Binds the tested value for the then branch only when it isn't nil
+            Expr::IfLet(name, test, then_branch, else_branch) => {
+                let name = if let Expr::Identifier(name) = *name {
+                    name
+                } else {
+                    return Err("Invalid variable name".to_string().into());
+                };
+                match eval_expr(*test, &env)? {
+                    ResultValue::Nil => expr = *else_branch,
+                    value => {
+                        let scope = Env::with_parent(env.clone());
+                        scope.borrow_mut().insert_vars(name, value);
+                        expr = *then_branch;
+                        env = scope;
+                    }
+                }
+            }
+
+            Expr::LetStar(bindings, body) => {
+                let bindings = binding_pairs(bindings)?;
+
+                // All bindings share one child scope, so each value sees the ones bound before it
+                let scope = Env::with_parent(env.clone());
+                for (name, value) in bindings {
+                    let value = eval_expr(value, &scope)?.named(&name);
+                    scope.borrow_mut().insert_vars(name, value);
+                }
+                expr = *body;
+                env = scope;
+            }
+
+            Expr::LetMany(bindings, body) => {
+                // Parallel let: every value is computed in the outer scope before any name is bound
+                let values = binding_pairs(bindings)?
+                    .into_iter()
+                    .map(|(name, value)| {
+                        let value = eval_expr(value, &env)?.named(&name);
+                        Ok((name, value))
+                    })
+                    .collect::<Result<Vec<_>, EvalError>>()?;
+
+                let scope = Env::with_parent(env.clone());
+                for (name, value) in values {
+                    scope.borrow_mut().insert_vars(name, value);
+                }
+                expr = *body;
+                env = scope;
+            }
+
+            Expr::LetRec(bindings, body) => {
+                let bindings = binding_pairs(bindings)?;
+
+                // Every name exists (as a placeholder) before any value is evaluated, so lambdas
+                // closing over this scope can refer to each other
+                let scope = Env::with_parent(env.clone());
+                for (name, _) in &bindings {
+                    scope.borrow_mut().insert_vars(name.clone(), ResultValue::Nil);
+                }
+                for (name, value) in bindings {
+                    let value = eval_expr(value, &scope)?.named(&name);
+                    scope.borrow_mut().insert_vars(name, value);
+                }
+                expr = *body;
+                env = scope;
+            }
+
+            Expr::Define(name, value) => {
+                let (name, value) = match *name {
+                    Expr::Identifier(name) => {
+                        let value = eval_expr(*value, &env)?.named(&name);
+                        (name, value)
+                    }
+                    // (define (name params...) body) is shorthand for binding name to a lambda
+                    Expr::Application(mut header) if !header.is_empty() => {
+                        let name = if let Expr::Identifier(name) = header.remove(0) {
+                            name
+                        } else {
+                            return Err("Invalid function name".to_string().into());
+                        };
+                        let params = parameter_names(header)?;
+                        let lambda = ResultValue::Lambda(Some(name.as_str().into()), params, Rc::new(*value), env.clone());
+                        (name, lambda)
+                    }
+                    _ => return Err("Invalid variable name".to_string().into()),
+                };
+                check_not_constant(&name)?;
+
+                // A lambda shares this scope, so it sees its own name once it's bound here (recursion)
+                env.borrow_mut().insert_vars(name, value);
+                return Ok(ResultValue::Nil);
+            }
+
+            Expr::Assignment(name, value) => {
+                let name = if let Expr::Identifier(name) = *name {
+                    name
+                } else {
+                    return Err("Invalid variable name".to_string().into());
+                };
+                check_not_constant(&name)?;
+                let value = eval_expr(*value, &env)?;
+                env.borrow_mut().update_vars(&name, value.clone())?;
+                return Ok(value);
+            }
+
+            Expr::Set(name, value) => {
+                let name = if let Expr::Identifier(name) = *name {
+                    name
+                } else {
+                    return Err("Invalid variable name".to_string().into());
+                };
+                check_not_constant(&name)?;
+                let value = eval_expr(*value, &env)?;
+                if !env.borrow_mut().set_var(&name, value) {
+                    return Err("Cannot set! unbound variable".to_string().into());
+                }
+                return Ok(ResultValue::Nil);
+            }
+
+            Expr::If(cond, then_branch, else_branch) => {
+                expr = match eval_expr(*cond, &env)? {
+                    ResultValue::Bool(true) => *then_branch,
+                    ResultValue::Bool(false) => *else_branch,
+                    _ => return Err("If condition must evaluate to a boolean".to_string().into()),
+                };
+            }
+
+            // Single-branch conditionals: the body runs (in tail position) only when the condition decides so
+            Expr::When(cond, body) => {
+                match eval_expr(*cond, &env)? {
+                    ResultValue::Bool(true) => expr = *body,
+                    ResultValue::Bool(false) => return Ok(ResultValue::Nil),
+                    _ => return Err("When condition must evaluate to a boolean".to_string().into()),
+                }
+            }
+
+            Expr::Unless(cond, body) => {
+                match eval_expr(*cond, &env)? {
+                    ResultValue::Bool(false) => expr = *body,
+                    ResultValue::Bool(true) => return Ok(ResultValue::Nil),
+                    _ => return Err("Unless condition must evaluate to a boolean".to_string().into()),
+                }
+            }
+
+            Expr::While(cond, body) => {
+                let mut result = ResultValue::Nil;
+                loop {
+                    match eval_expr((*cond).clone(), &env)? {
+                        ResultValue::Bool(true) => result = eval_expr((*body).clone(), &env)?,
+                        ResultValue::Bool(false) => return Ok(result),
+                        _ => return Err("While condition must evaluate to a boolean".to_string().into()),
+                    }
+                }
+            }
+
+            // An error in the body is recovered by the handler, which sees the message as `error-message`
+            Expr::Try(body, handler) => match eval_expr(*body, &env) {
+                Ok(value) => return Ok(value),
+                Err(e) => {
+                    let scope = Env::with_parent(env.clone());
+                    scope.borrow_mut().insert_vars("error-message".to_string(), ResultValue::String(e.message));
+                    expr = *handler;
+                    env = scope;
+                }
+            },
+
+            // Printed rather than returned, since the result printer would show the dump as one escaped string
+            Expr::DumpEnv => {
+                let dump = env.borrow().dump();
+                if !dump.is_empty() {
+                    println!("{}", dump);
+                }
+                return Ok(ResultValue::Nil);
+            }
+
+            // Reports how long the expression took on stderr, leaving stdout to the program
+            Expr::Time(timed) => {
+                let start = Instant::now();
+                let result = eval_expr(*timed, &env);
+                eprintln!("time: {:?}", start.elapsed());
+                return result;
+            }
+
+            // Counted loop: each iteration binds the variable in its own child scope, so it never leaks out
+            Expr::Do(name, start, end, body) => {
+                let name = if let Expr::Identifier(name) = *name {
+                    name
+                } else {
+                    return Err("Invalid variable name".to_string().into());
+                };
+                let (start, end) = match (eval_expr(*start, &env)?, eval_expr(*end, &env)?) {
+                    (ResultValue::Number(start), ResultValue::Number(end)) => (start, end),
+                    _ => return Err("do bounds must be numbers".to_string().into()),
+                };
+                for n in start..end {
+                    let scope = Env::with_parent(env.clone());
+                    scope.borrow_mut().insert_vars(name.clone(), ResultValue::Number(n));
+                    eval_expr((*body).clone(), &scope)?;
+                }
+                return Ok(ResultValue::Nil);
+            }
+
+            // `and`/`or` are special forms rather than builtins so they can stop at the first deciding operand.
+            // They return that operand itself (see is_truthy): and gives the first falsy operand or else
+            // the last one, or gives the first truthy operand or else the last one.
+            Expr::And(exprs) => {
+                let mut result = ResultValue::Bool(true);
+                for expr in exprs {
+                    result = eval_expr(expr, &env)?;
+                    if !result.is_truthy() {
+                        break;
+                    }
+                }
+                return Ok(result);
+            }
+
+            Expr::Or(exprs) => {
+                let mut result = ResultValue::Bool(false);
+                for expr in exprs {
+                    result = eval_expr(expr, &env)?;
+                    if result.is_truthy() {
+                        break;
+                    }
+                }
+                return Ok(result);
+            }
+        }
+        debug_assert!(may_loop, "a form missing from is_tail_position continued in tail position");
+    }
+}
+
+// What is left of a call made from tail position once enter_call has dealt with it
+enum TailCall {
+    // A lambda's body, to be evaluated in the scope holding its arguments
+    Body(Expr, SharedEnv),
+    // The value of any other callable, which has already been called
+    Done(ResultValue),
+}
+
+// Calls `func` from eval_tail's loop. A lambda isn't evaluated here: its arguments are bound and its
+// body handed back, so the loop continues in it without taking Rust stack.
+fn enter_call(
+    func: ResultValue,
+    arg_values: Vec<ResultValue>,
+    env: &SharedEnv,
+    tail_call: &mut Option<(SharedEnv, usize)>,
+    frames: &mut Vec<String>,
+) -> Result<TailCall, EvalError> {
+    match func {
+        ResultValue::Lambda(name, param_names, body, lambda_env) => {
+            // Under dynamic scope the callee still sees the caller's bindings, so the
+            // frame being replaced is flattened into the new one rather than kept as a parent
+            let scope = match (&*tail_call, use_lexical_scope()) {
+                (_, true) => Env::with_parent(lambda_env),
+                (Some((frame, _)), false) => Env::flatten(env, frame),
+                (None, false) => Env::with_parent(env.clone()),
+            };
+            bind_arguments(&scope, name.as_deref(), param_names, arg_values)?;
+
+            // Only the innermost lambda body of a chain of tail calls stays on the error path
+            let base = match &*tail_call {
+                Some((_, base)) => *base,
+                None => frames.len(),
+            };
+            frames.truncate(base);
+            frames.push("Lambda body".to_string());
+            *tail_call = Some((scope.clone(), base));
+            Ok(TailCall::Body(Rc::unwrap_or_clone(body), scope))
+        }
+        func => call_function(func, arg_values, env).map(TailCall::Done),
+    }
+}
+
+// Turns an unevaluated expression into data: identifiers become symbols and applications become lists
+fn quote_expr(expr: Expr) -> Result<ResultValue, String> {
+    match expr {
+        Expr::Identifier(name) => Ok(ResultValue::Symbol(name)),
+        Expr::Number(n) => Ok(ResultValue::Number(n)),
+        Expr::NumberStr(literal) => parse_number_literal(&literal).map(ResultValue::Number),
+        Expr::Float(x) => Ok(ResultValue::Float(x)),
+        Expr::Bool(b) => Ok(ResultValue::Bool(b)),
+        Expr::String(s) => Ok(ResultValue::String(s)),
+        Expr::Application(items) => items
+            .into_iter()
+            .map(quote_expr)
+            .collect::<Result<Vec<_>, _>>()
+            .map(ResultValue::List),
+        _ => Err("quote supports identifiers, literals and applications".to_string()),
+    }
+}
+
+// The inverse of quote_expr: turns quoted data back into an expression that can be evaluated
+fn unquote_value(value: ResultValue) -> Result<Expr, String> {
+    match value {
+        ResultValue::Symbol(name) => Ok(Expr::Identifier(name)),
+        ResultValue::Number(n) => Ok(Expr::Number(n)),
+        ResultValue::Float(x) => Ok(Expr::Float(x)),
+        ResultValue::Bool(b) => Ok(Expr::Bool(b)),
+        ResultValue::String(s) => Ok(Expr::String(s)),
+        ResultValue::List(items) => {
+            if items.is_empty() {
+                return Err("Cannot evaluate an empty list".to_string());
+            }
+            items
+                .into_iter()
+                .map(unquote_value)
+                .collect::<Result<Vec<_>, _>>()
+                .map(Expr::Application)
+        }
+        _ => Err("Value is not evaluable code".to_string()),
+    }
+}
+
+// Static structural check of a program, reporting every problem evaluation would hit because of
+// the tree's shape (not its values) without running anything
+pub fn validate_expr(expr: &Expr) -> Result<(), Vec<String>> {
+    let mut errors = Vec::new();
+    collect_structure_errors(expr, &mut errors);
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+fn collect_structure_errors(expr: &Expr, errors: &mut Vec<String>) {
+    let check_name = |name: &Expr, errors: &mut Vec<String>| {
+        if !matches!(name, Expr::Identifier(_)) {
+            errors.push("Invalid variable name".to_string());
+        }
+    };
+    let children: Vec<&Expr> = match expr {
+        Expr::Identifier(_) | Expr::Number(_) | Expr::Float(_) | Expr::Bool(_) | Expr::String(_) | Expr::DumpEnv => vec![],
+        // Quoted expressions are data, checked only when they're evaluated
+        Expr::Quote(_) => vec![],
+        Expr::NumberStr(literal) => {
+            if let Err(e) = parse_number_literal(literal) {
+                errors.push(e);
+            }
+            vec![]
+        }
+        Expr::Application(items) => {
+            if items.is_empty() {
+                errors.push("Empty application".to_string());
+            }
+            items.iter().collect()
+        }
+        Expr::Block(exprs) | Expr::Scope(exprs) | Expr::And(exprs) | Expr::Or(exprs) => exprs.iter().collect(),
+        Expr::Begin(exprs) => {
+            if exprs.is_empty() {
+                errors.push("empty begin".to_string());
+            }
+            exprs.iter().collect()
+        }
+        Expr::Cond(clauses) => {
+            let mut children = Vec::new();
+            for (index, clause) in clauses.iter().enumerate() {
+                match clause {
+                    Expr::Clause(parts) if parts.is_empty() => {
+                        errors.push("Each clause must have at least 1 expression".to_string());
+                    }
+                    Expr::Clause(parts) => {
+                        if matches!(&parts[0], Expr::Identifier(name) if name == "else") && index + 1 != clauses.len() {
+                            errors.push("else must be the final clause".to_string());
+                        }
+                        children.extend(parts);
+                    }
+                    _ => errors.push("Invalid clause".to_string()),
+                }
+            }
+            children
+        }
+        Expr::Case(key, clauses) => {
+            let mut children = vec![key.as_ref()];
+            for (index, clause) in clauses.iter().enumerate() {
+                match clause {
+                    Expr::Clause(parts) if parts.len() >= 2 => {
+                        if matches!(&parts[0], Expr::Identifier(name) if name == "else") && index + 1 != clauses.len() {
+                            errors.push("else must be the final clause".to_string());
+                        }
+                        children.extend(&parts[1..]);
+                    }
+                    _ => errors.push("Each case clause must have constants and a body".to_string()),
+                }
+            }
+            children
+        }
+        Expr::Clause(_) => {
+            errors.push("Invalid clause not wrapped in a cond".to_string());
+            vec![]
+        }
+        Expr::Parameters(_) => {
+            errors.push("Invalid parameters not wrapped in a lambda".to_string());
+            vec![]
+        }
+        Expr::Lambda(parts) => {
+            if parts.len() != 2 {
+                errors.push("Lambda must have exactly 2 expressions".to_string());
+                return;
+            }
+            match &parts[0] {
+                Expr::Parameters(params) if params.iter().all(|param| matches!(param, Expr::Identifier(_))) => {}
+                Expr::Parameters(_) => errors.push("Invalid parameter".to_string()),
+                _ => errors.push("Invalid parameters".to_string()),
+            }
+            vec![&parts[1]]
+        }
+        Expr::Define(name, value) => {
+            match name.as_ref() {
+                // (define (name params...) body)
+                Expr::Application(header) if !header.is_empty() => {
+                    if !matches!(&header[0], Expr::Identifier(_)) {
+                        errors.push("Invalid function name".to_string());
+                    }
+                    if !header[1..].iter().all(|param| matches!(param, Expr::Identifier(_))) {
+                        errors.push("Invalid parameter".to_string());
+                    }
+                }
+                name => check_name(name, errors),
+            }
+            vec![value]
+        }
+        Expr::Let(name, value, body) => {
+            check_name(name, errors);
+            vec![value, body]
+        }
+        Expr::Assignment(name, value) | Expr::Set(name, value) => {
+            check_name(name, errors);
+            vec![value]
+        }
+        Expr::IfLet(name, test, then, otherwise) => {
+            check_name(name, errors);
+            vec![test, then, otherwise]
+        }
+        Expr::Do(name, start, end, body) => {
+            check_name(name, errors);
+            vec![start, end, body]
+        }
+        Expr::Defined(name) => {
+            if !matches!(name.as_ref(), Expr::Identifier(_)) {
+                errors.push("defined? expects an identifier".to_string());
+            }
+            vec![]
+        }
+        Expr::LetStar(bindings, body) | Expr::LetMany(bindings, body) | Expr::LetRec(bindings, body) => {
+            let mut children = Vec::new();
+            for binding in bindings {
+                match binding {
+                    Expr::Clause(pair) if pair.len() == 2 => {
+                        check_name(&pair[0], errors);
+                        children.push(&pair[1]);
+                    }
+                    _ => errors.push("Each binding must be a clause of a name and a value".to_string()),
+                }
+            }
+            children.push(body);
+            children
+        }
+        Expr::If(condition, then, otherwise) => vec![condition, then, otherwise],
+        Expr::While(condition, body) | Expr::When(condition, body) | Expr::Unless(condition, body) => vec![condition, body],
+        Expr::Try(body, handler) => vec![body, handler],
+        Expr::Time(timed) => vec![timed],
+    };
+    for child in children {
+        collect_structure_errors(child, errors);
+    }
+}
+
+// Parses an integer literal with an optional minus sign and a 0x, 0b or 0o base prefix
+fn parse_number_literal(literal: &str) -> Result<i64, String> {
+    let (sign, unsigned) = match literal.strip_prefix('-') {
+        Some(rest) => ("-", rest),
+        None => ("", literal),
+    };
+    let (radix, digits) = match unsigned.get(..2) {
+        Some("0x" | "0X") => (16, &unsigned[2..]),
+        Some("0b" | "0B") => (2, &unsigned[2..]),
+        Some("0o" | "0O") => (8, &unsigned[2..]),
+        _ => (10, unsigned),
+    };
+    // A sign inside the digits (like "0x-1") is not a valid literal
+    if digits.starts_with(['+', '-']) {
+        return Err("invalid number literal".to_string());
+    }
+    i64::from_str_radix(&format!("{}{}", sign, digits), radix).map_err(|_| "invalid number literal".to_string())
+}
+
+// Integers parse to a Number, anything with a fractional part or exponent to a Float. NaN and the
+// infinities are not numbers, however they're spelled ("inf", "NaN", or "1e999", which overflows).
+fn parse_decimal(text: &str) -> Option<ResultValue> {
+    match text.parse::<i64>() {
+        Ok(n) => Some(ResultValue::Number(n)),
+        Err(_) => text.parse::<f64>().ok().filter(|x| x.is_finite()).map(ResultValue::Float),
+    }
+}
+
+// Renders the AST as indented s-expression text; nodes whose children are all atoms stay on one line
+pub fn pretty_print_expr(expr: &Expr, indent: usize) -> String {
+    let pad = " ".repeat(indent);
+    let (label, children): (Option<&str>, Vec<&Expr>) = match expr {
+        Expr::Identifier(name) => return format!("{}{}", pad, name),
+        Expr::Number(n) => return format!("{}{}", pad, n),
+        Expr::NumberStr(literal) => return format!("{}{}", pad, literal),
+        Expr::DumpEnv => return format!("{}(dump-env)", pad),
+        Expr::Float(x) => return format!("{}{:?}", pad, x),
+        Expr::Bool(b) => return format!("{}{}", pad, b),
+        Expr::String(s) => return format!("{}{:?}", pad, s),
+        Expr::Application(items) | Expr::Clause(items) | Expr::Parameters(items) => (None, items.iter().collect()),
+        Expr::Cond(clauses) => (Some("cond"), clauses.iter().collect()),
+        Expr::Case(key, clauses) => (Some("case"), std::iter::once(key.as_ref()).chain(clauses).collect()),
+        Expr::Block(exprs) => (Some("block"), exprs.iter().collect()),
+        Expr::Scope(exprs) => (Some("scope"), exprs.iter().collect()),
+        Expr::Begin(exprs) => (Some("begin"), exprs.iter().collect()),
+        Expr::And(exprs) => (Some("and"), exprs.iter().collect()),
+        Expr::Or(exprs) => (Some("or"), exprs.iter().collect()),
+        Expr::Lambda(parts) => (Some("lambda"), parts.iter().collect()),
+        Expr::Let(name, value, body) => (Some("let"), vec![name, value, body]),
+        Expr::Define(name, value) => (Some("define"), vec![name, value]),
+        Expr::Assignment(name, value) => (Some("assign"), vec![name, value]),
+        Expr::Set(name, value) => (Some("set!"), vec![name, value]),
+        Expr::If(condition, then, otherwise) => (Some("if"), vec![condition, then, otherwise]),
+        Expr::IfLet(name, test, then, otherwise) => (Some("if-let"), vec![name, test, then, otherwise]),
+        Expr::While(condition, body) => (Some("while"), vec![condition, body]),
+        Expr::Try(body, handler) => (Some("try"), vec![body, handler]),
+        Expr::Do(name, start, end, body) => (Some("do"), vec![name, start, end, body]),
+        Expr::When(condition, body) => (Some("when"), vec![condition, body]),
+        Expr::Unless(condition, body) => (Some("unless"), vec![condition, body]),
+        Expr::Quote(quoted) => (Some("quote"), vec![quoted]),
+        Expr::Time(timed) => (Some("time"), vec![timed]),
+        Expr::Defined(name) => (Some("defined?"), vec![name]),
+        Expr::LetStar(bindings, body) | Expr::LetMany(bindings, body) | Expr::LetRec(bindings, body) => {
+            let label = match expr {
+                Expr::LetStar(_, _) => "let*",
+                Expr::LetMany(_, _) => "let-many",
+                _ => "letrec",
+            };
+            let bindings = pretty_print_expr(&Expr::Parameters(bindings.clone()), indent + 2);
+            return format!("{}({}\n{}\n{})", pad, label, bindings, pretty_print_expr(body, indent + 2));
+        }
+    };
+
+    let is_atom = |expr: &Expr| {
+        matches!(expr, Expr::Identifier(_) | Expr::Number(_) | Expr::NumberStr(_) | Expr::Float(_) | Expr::Bool(_) | Expr::String(_))
+    };
+    let mut parts: Vec<String> = label.map(str::to_string).into_iter().collect();
+    if children.iter().all(|child| is_atom(child)) {
+        parts.extend(children.iter().map(|child| pretty_print_expr(child, 0)));
+        return format!("{}({})", pad, parts.join(" "));
+    }
+
+    // Leading atoms (a function or variable name) stay on the opening line with the label
+    let mut children = children.into_iter().peekable();
+    while let Some(child) = children.next_if(|child| is_atom(child)) {
+        parts.push(pretty_print_expr(child, 0));
+    }
+    let mut out = format!("{}({}", pad, parts.join(" "));
+    let mut child_indent = indent + 2;
+    if parts.is_empty() {
+        // A list of compound items, like let bindings, starts its first item right after the paren
+        if let Some(first) = children.next() {
+            out.push_str(pretty_print_expr(first, indent + 1).trim_start());
+        }
+        child_indent = indent + 1;
+    }
+    for child in children {
+        out.push('\n');
+        out.push_str(&pretty_print_expr(child, child_indent));
+    }
+    out.push(')');
+    out
+}
+
+fn parameter_names(params: Vec<Expr>) -> Result<Vec<String>, String> {
+    params.into_iter().map(|param| {
+        if let Expr::Identifier(name) = param {
+            Ok(name)
+        } else {
+            Err("Invalid parameter".to_string())
+        }
+    }).collect()
+}
+
+// Splits `Clause([Identifier(name), value])` bindings into name/value pairs
+fn binding_pairs(bindings: Vec<Expr>) -> Result<Vec<(String, Expr)>, String> {
+    bindings
+        .into_iter()
+        .map(|binding| match binding {
+            Expr::Clause(mut pair) if pair.len() == 2 => {
+                let value = pair.remove(1);
+                match pair.remove(0) {
+                    Expr::Identifier(name) => Ok((name, value)),
+                    _ => Err("Invalid variable name".to_string()),
+                }
+            }
+            _ => Err("Each binding must be a clause of a name and a value".to_string()),
+        })
+        .collect()
+}
+
+fn eval_arguments(args: Vec<Expr>, env: &SharedEnv) -> Result<Vec<ResultValue>, EvalError> {
+    args.into_iter()
+        .enumerate()
+        .map(|(index, arg)| eval_expr(arg, env).map_err(|e| e.within(format!("Application[{}]", index + 1))))
+        .collect()
+}
+
+// "f: expected 1 argument, got 2"; the name is left off for anonymous lambdas
+fn arity_error(name: Option<&str>, expected: usize, got: usize) -> EvalError {
+    let plural = if expected == 1 { "" } else { "s" };
+    let message = format!("expected {} argument{}, got {}", expected, plural, got);
+    match name {
+        Some(name) => format!("{}: {}", name, message).into(),
+        None => message.into(),
+    }
+}
+
+// Binds a lambda's parameters to the argument values in its fresh call scope
+fn bind_arguments(scope: &SharedEnv, name: Option<&str>, param_names: Vec<String>, args: Vec<ResultValue>) -> Result<(), EvalError> {
+    if args.len() != param_names.len() {
+        return Err(arity_error(name, param_names.len(), args.len()));
+    }
+    for (param_name, arg_value) in param_names.into_iter().zip(args) {
+        scope.borrow_mut().insert_vars(param_name, arg_value);
+    }
+    Ok(())
+}
+
+// Applies a callable to arguments that have already been evaluated, so builtins like `map` can call back into it
+fn call_function(f: ResultValue, args: Vec<ResultValue>, env: &SharedEnv) -> Result<ResultValue, EvalError> {
+    match f {
+        ResultValue::Func(name, args_length, func) => {
+            if args.len() != args_length {
+                return Err(arity_error(Some(&name), args_length, args.len()));
+            }
+
+            Ok(func(args)?)
+        }
+        ResultValue::VarFunc(func) => Ok(func(args)?),
+        ResultValue::EnvFunc(name, args_length, func) => {
+            if args.len() != args_length {
+                return Err(arity_error(Some(&name), args_length, args.len()));
+            }
+
+            func(args, env)
+        }
+        ResultValue::Lambda(name, param_names, body, lambda_env) => {
+            // Bind the arguments in a fresh scope on top of the defining scope (lexical)
+            // or the calling scope (dynamic)
+            let scope = if use_lexical_scope() {
+                Env::with_parent(lambda_env)
+            } else {
+                Env::with_parent(env.clone())
+            };
+            bind_arguments(&scope, name.as_deref(), param_names, args)?;
+
+            eval_expr(Rc::unwrap_or_clone(body), &scope).map_err(|e| e.within("Lambda body"))
+        }
+        ResultValue::Composed(f, g) => {
+            let inner = call_function(Rc::unwrap_or_clone(g), args, env)?;
+            call_function(Rc::unwrap_or_clone(f), vec![inner], env)
+        }
+        // Arity is checked by f against the captured and new arguments together
+        ResultValue::Curried(f, mut captured) => {
+            captured.extend(args);
+            call_function(Rc::unwrap_or_clone(f), captured, env)
+        }
+        ResultValue::Memoized(f, cache) => {
+            if args.len() != 1 {
+                return Err(arity_error(f.name(), 1, args.len()));
+            }
+            // Only numbers, strings and bools are cached; other arguments just call through
+            let key = match &args[0] {
+                key @ (ResultValue::Number(_) | ResultValue::String(_) | ResultValue::Bool(_)) => format!("{:?}", key),
+                _ => return call_function(*f, args, env),
+            };
+            if let Some(value) = cache.borrow().get(&key) {
+                return Ok(value.clone());
+            }
+            let value = call_function(*f, args, env)?;
+            cache.borrow_mut().insert(key, value.clone());
+            Ok(value)
+        }
+        _ => Err("Not a function".to_string().into()),
+    }
+}
+
+// Set by `--trace` or TRACE=1 to log every evaluation step to stderr
+static TRACE: AtomicBool = AtomicBool::new(false);
+
+// Scoping strategy for free variables in lambdas: lexical, so closures keep their defining scope,
+// unless `--dynamic` is given
+static LEXICAL_SCOPE: AtomicBool = AtomicBool::new(true);
+
+// Nesting limit for eval_expr (`--max-depth=N`). The limit only helps if the thread evaluating has
+// the Rust stack for it; with_eval_stack makes a thread that does.
+static MAX_DEPTH: AtomicUsize = AtomicUsize::new(10000);
+
+// Rust stack one level of eval_expr nesting may take; unoptimized builds take far more
+const STACK_PER_DEPTH: usize = if cfg!(debug_assertions) { 64 * 1024 } else { 16 * 1024 };
+
+thread_local! {
+    // Current nesting of eval_expr, and of traced evaluations, counted per thread so evaluations
+    // running side by side don't use up each other's limit
+    static EVAL_DEPTH: Cell<usize> = const { Cell::new(0) };
+    static TRACE_DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+// Source of fresh `gensym` names; it only ever counts up, so no two calls share a symbol
+static GENSYM_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+fn use_lexical_scope() -> bool {
+    LEXICAL_SCOPE.load(Ordering::Relaxed)
+}
+
+// Interpreter-wide settings; they apply to every environment in the process
+pub fn set_trace(enabled: bool) {
+    TRACE.store(enabled, Ordering::Relaxed);
+}
+
+pub fn set_lexical_scope(lexical: bool) {
+    LEXICAL_SCOPE.store(lexical, Ordering::Relaxed);
+}
+
+pub fn max_depth() -> usize {
+    MAX_DEPTH.load(Ordering::Relaxed)
+}
+
+pub fn set_max_depth(limit: usize) {
+    MAX_DEPTH.store(limit, Ordering::Relaxed);
+}
+
+// Runs `f` on a thread with the Rust stack for max_depth() nested evaluations, so a program that
+// recurses too deeply ends in the "Maximum recursion depth exceeded" error instead of overflowing
+// the stack. At the default limit of 10000 that is 160MB (640MB in unoptimized builds), far more
+// than the 8MB main thread or 2MB spawned thread an embedder evaluates on otherwise; the stack
+// grows with set_max_depth, and the only error is when the system can't provide it. Environments
+// and values stay on the new thread, so `f` sets them up and hands back something plain.
+pub fn with_eval_stack<T: Send>(f: impl FnOnce() -> T + Send) -> io::Result<T> {
+    let stack_size = max_depth().saturating_mul(STACK_PER_DEPTH);
+    std::thread::scope(|scope| {
+        let evaluator = std::thread::Builder::new().stack_size(stack_size).spawn_scoped(scope, f)?;
+        // A panic while evaluating carries on in the calling thread
+        Ok(evaluator.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+    })
+}
+
+// Reads the next line of stdin without its line ending, or None at EOF. Stdin is buffered once for
+// the whole process, so in the REPL `read-line` takes the line after the expression being
+// evaluated, and when the program itself came from stdin there is nothing left to read.
+pub fn read_stdin_line() -> Result<Option<String>, String> {
+    let mut line = String::new();
+    match io::stdin().read_line(&mut line) {
+        Ok(0) => Ok(None),
+        Ok(_) => {
+            let trimmed = line.trim_end_matches(['\n', '\r']).len();
+            line.truncate(trimmed);
+            Ok(Some(line))
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ident(name: &str) -> Expr {
+        Expr::Identifier(name.to_string())
+    }
+
+    fn num(n: i64) -> Expr {
+        Expr::Number(n)
+    }
+
+    fn call(name: &str, args: Vec<Expr>) -> Expr {
+        Expr::Application(std::iter::once(ident(name)).chain(args).collect())
+    }
+
+    // Evaluates a program in a fresh environment, on a thread with room for deep recursion, giving
+    // the value of its last expression in written form
+    fn run(program: Vec<Expr>) -> Result<String, EvalError> {
+        with_eval_stack(move || -> Result<String, EvalError> {
+            let env = Rc::new(RefCell::new(Env::new(&[])));
+            let mut value = String::new();
+            for expr in program {
+                value = Written(&eval_expr(expr, &env)?).to_string();
+            }
+            Ok(value)
+        })
+        .expect("the evaluation thread should start")
+    }
+
+    // The program's result, or its error message after "Error: "
+    fn eval_program(program: Vec<Expr>) -> String {
+        run(program).unwrap_or_else(|e| format!("Error: {}", e.message))
+    }
+
+    fn eval(expr: Expr) -> String {
+        eval_program(vec![expr])
+    }
+
+    fn float(x: f64) -> Expr {
+        Expr::Float(x)
+    }
+
+    fn string(s: &str) -> Expr {
+        Expr::String(s.to_string())
+    }
+
+    fn if_expr(condition: Expr, then: Expr, otherwise: Expr) -> Expr {
+        Expr::If(Box::new(condition), Box::new(then), Box::new(otherwise))
+    }
+
+    fn cond(clauses: Vec<Vec<Expr>>) -> Expr {
+        Expr::Cond(clauses.into_iter().map(Expr::Clause).collect())
+    }
+
+    fn boolean(b: bool) -> Expr {
+        Expr::Bool(b)
+    }
+
+    fn list(items: Vec<Expr>) -> Expr {
+        call("list", items)
+    }
+
+    fn lambda(params: &[&str], body: Expr) -> Expr {
+        Expr::Lambda(vec![Expr::Parameters(params.iter().map(|param| ident(param)).collect()), body])
+    }
+
+    fn boxed(name: &str) -> Box<Expr> {
+        Box::new(ident(name))
+    }
+
+    fn define(name: &str, value: Expr) -> Expr {
+        Expr::Define(boxed(name), Box::new(value))
+    }
+
+    fn let_expr(name: &str, value: Expr, body: Expr) -> Expr {
+        Expr::Let(boxed(name), Box::new(value), Box::new(body))
+    }
+
+    // Name/value clauses, as the let*, parallel let and letrec forms take them
+    fn bindings(pairs: Vec<(&str, Expr)>) -> Vec<Expr> {
+        pairs.into_iter().map(|(name, value)| Expr::Clause(vec![ident(name), value])).collect()
+    }
+
+    fn set(name: &str, value: Expr) -> Expr {
+        Expr::Set(boxed(name), Box::new(value))
+    }
+
+    fn begin(exprs: Vec<Expr>) -> Expr {
+        Expr::Begin(exprs)
+    }
+
+    fn quote(expr: Expr) -> Expr {
+        Expr::Quote(Box::new(expr))
+    }
+
+    // A call whose head is an arbitrary expression rather than a name
+    fn call_value(head: Expr, args: Vec<Expr>) -> Expr {
+        Expr::Application(std::iter::once(head).chain(args).collect())
+    }
+
+    fn number_literal(literal: &str) -> Expr {
+        Expr::NumberStr(literal.to_string())
+    }
+
+    fn case(key: Expr, clauses: Vec<Vec<Expr>>) -> Expr {
+        Expr::Case(Box::new(key), clauses.into_iter().map(Expr::Clause).collect())
+    }
+
+    fn rational(numerator: i64, denominator: i64) -> Expr {
+        call("rational", vec![num(numerator), num(denominator)])
+    }
+
+    #[test]
+    fn mod_is_euclidean_and_rem_takes_the_sign_of_the_dividend() {
+        assert_eq!(eval(call("mod", vec![num(-7), num(3)])), "2");
+        assert_eq!(eval(call("rem", vec![num(-7), num(3)])), "-1");
+        assert_eq!(eval(call("mod", vec![num(7), num(-3)])), "1");
+        assert_eq!(eval(call("rem", vec![num(7), num(-3)])), "1");
+        assert_eq!(eval(call("mod", vec![num(-7), num(-3)])), "2");
+        assert_eq!(eval(call("rem", vec![num(-7), num(-3)])), "-1");
+        assert_eq!(eval(call("mod", vec![num(6), num(3)])), "0");
+    }
+
+    #[test]
+    fn mod_and_rem_by_zero_are_errors() {
+        assert_eq!(eval(call("mod", vec![num(1), num(0)])), "Error: Modulo by zero");
+        assert_eq!(eval(call("rem", vec![num(1), num(0)])), "Error: Modulo by zero");
+    }
+
+    #[test]
+    fn arithmetic_promotes_to_float_when_either_operand_is_one() {
+        assert_eq!(eval(call("add", vec![num(1), float(2.5)])), "3.5");
+        assert_eq!(eval(call("sub", vec![float(0.5), num(2)])), "-1.5");
+        assert_eq!(eval(call("mul", vec![num(2), float(1.5)])), "3.0");
+        assert_eq!(eval(call("div", vec![float(1.0), num(4)])), "0.25");
+        assert_eq!(eval(call("pow", vec![num(4), float(0.5)])), "2.0");
+    }
+
+    #[test]
+    fn floats_always_print_with_a_fractional_part() {
+        assert_eq!(eval(float(3.0)), "3.0");
+        assert_eq!(eval(float(2.75)), "2.75");
+    }
+
+    #[test]
+    fn div_of_two_numbers_stays_integer_and_fdiv_keeps_the_fraction() {
+        assert_eq!(eval(call("div", vec![num(7), num(2)])), "3");
+        assert_eq!(eval(call("fdiv", vec![num(7), num(2)])), "3.5");
+        assert_eq!(eval(call("fdiv", vec![num(1), num(0)])), "Error: Division by zero");
+    }
+
+    #[test]
+    fn if_evaluates_one_branch() {
+        assert_eq!(eval(if_expr(call("zero?", vec![num(0)]), num(1), num(2))), "1");
+        assert_eq!(eval(if_expr(call("zero?", vec![num(5)]), num(1), num(2))), "2");
+        // The branch not taken is never evaluated
+        assert_eq!(eval(if_expr(call("zero?", vec![num(0)]), num(1), call("div", vec![num(1), num(0)]))), "1");
+    }
+
+    #[test]
+    fn ifs_nest() {
+        let inner = if_expr(call("zero?", vec![num(1)]), num(1), num(2));
+        assert_eq!(eval(if_expr(call("zero?", vec![num(0)]), inner.clone(), num(3))), "2");
+        assert_eq!(eval(if_expr(call("zero?", vec![num(1)]), num(3), inner)), "2");
+    }
+
+    #[test]
+    fn if_condition_must_be_a_boolean() {
+        let program = if_expr(string("true"), num(1), num(2));
+        assert_eq!(eval(program), "Error: If condition must evaluate to a boolean");
+    }
+
+    #[test]
+    fn cond_takes_the_first_clause_whose_test_is_true() {
+        let program = cond(vec![
+            vec![call("zero?", vec![num(1)]), num(1)],
+            vec![call("zero?", vec![num(0)]), num(2)],
+            vec![call("zero?", vec![num(0)]), num(3)],
+        ]);
+        assert_eq!(eval(program), "2");
+    }
+
+    #[test]
+    fn cond_matches_any_truthy_test() {
+        let program = cond(vec![vec![ident("nil"), num(1)], vec![string("true"), num(2)]]);
+        assert_eq!(eval(program), "2");
+        assert_eq!(eval(cond(vec![vec![num(0), num(3)]])), "3");
+    }
+
+    #[test]
+    fn else_matches_when_no_other_clause_does() {
+        let program = cond(vec![vec![boolean(false), num(1)], vec![ident("else"), num(2)]]);
+        assert_eq!(eval(program), "2");
+        let program = cond(vec![vec![boolean(true), num(1)], vec![ident("else"), num(2)]]);
+        assert_eq!(eval(program), "1");
+    }
+
+    #[test]
+    fn else_must_be_the_final_clause() {
+        let program = cond(vec![vec![ident("else"), num(1)], vec![boolean(true), num(2)]]);
+        assert_eq!(eval(program), "Error: else must be the final clause");
+    }
+
+    #[test]
+    fn bool_literals_evaluate_to_themselves() {
+        let program: Expr = serde_json::from_str(r#"{"Bool": false}"#).unwrap();
+        assert_eq!(eval(program), "false");
+        assert_eq!(eval(boolean(true)), "true");
+    }
+
+    #[test]
+    fn and_and_or_stop_at_the_deciding_operand() {
+        // The division by zero would fail if it were evaluated
+        let failing = call("div", vec![num(1), num(0)]);
+        assert_eq!(eval(Expr::And(vec![boolean(false), failing.clone()])), "false");
+        assert_eq!(eval(Expr::Or(vec![boolean(true), failing.clone()])), "true");
+        assert_eq!(eval(Expr::And(vec![boolean(true), failing])), "Error: Division by zero");
+    }
+
+    #[test]
+    fn not_negates_a_boolean() {
+        assert_eq!(eval(call("not", vec![boolean(true)])), "false");
+        assert_eq!(eval(call("not", vec![boolean(false)])), "true");
+        assert_eq!(eval(call("not", vec![num(1)])), "Error: Invalid argument");
+    }
+
+    #[test]
+    fn not_equal_compares_numbers_booleans_and_strings() {
+        assert_eq!(eval(call("!=", vec![num(1), num(2)])), "true");
+        assert_eq!(eval(call("!=", vec![num(2), num(2)])), "false");
+        assert_eq!(eval(call("!=", vec![boolean(true), boolean(false)])), "true");
+        assert_eq!(eval(call("!=", vec![string("a"), string("a")])), "false");
+    }
+
+    #[test]
+    fn not_equal_rejects_mismatched_types_and_arity() {
+        assert_eq!(eval(call("!=", vec![num(1), string("1")])), "Error: Invalid arguments");
+        assert_eq!(eval(call("!=", vec![num(1)])), "Error: !=: expected 2 arguments, got 1");
+    }
+
+    #[test]
+    fn equal_compares_strings_and_booleans() {
+        assert_eq!(eval(call("equal", vec![string("abc"), string("abc")])), "true");
+        assert_eq!(eval(call("equal", vec![string("abc"), string("abd")])), "false");
+        assert_eq!(eval(call("equal", vec![boolean(true), boolean(true)])), "true");
+        assert_eq!(eval(call("equal", vec![boolean(true), boolean(false)])), "false");
+    }
+
+    #[test]
+    fn equal_rejects_mismatched_types() {
+        assert_eq!(eval(call("equal", vec![num(1), string("1")])), "Error: Invalid arguments");
+        assert_eq!(eval(call("equal", vec![boolean(true), num(1)])), "Error: Invalid arguments");
+    }
+
+    #[test]
+    fn min_and_max_pick_from_their_arguments() {
+        assert_eq!(eval(call("min", vec![num(3), num(1)])), "1");
+        assert_eq!(eval(call("max", vec![num(3), num(1)])), "3");
+        assert_eq!(eval(call("min", vec![num(-2), num(-2)])), "-2");
+    }
+
+    #[test]
+    fn min_and_max_reject_non_numbers() {
+        assert_eq!(eval(call("max", vec![num(1), string("2")])), "Error: Invalid arguments");
+    }
+
+    #[test]
+    fn abs_neg_and_sign() {
+        assert_eq!(eval(call("abs", vec![num(-5)])), "5");
+        assert_eq!(eval(call("abs", vec![num(5)])), "5");
+        assert_eq!(eval(call("neg", vec![num(5)])), "-5");
+        assert_eq!(eval(call("sign", vec![num(-3)])), "-1");
+        assert_eq!(eval(call("sign", vec![num(0)])), "0");
+        assert_eq!(eval(call("sign", vec![num(7)])), "1");
+    }
+
+    #[test]
+    fn abs_of_the_smallest_number_is_an_overflow_error() {
+        assert_eq!(eval(call("abs", vec![num(i64::MIN)])), "Error: Integer overflow");
+        assert_eq!(eval(call("neg", vec![num(i64::MIN)])), "Error: Integer overflow");
+    }
+
+    #[test]
+    fn abs_neg_and_sign_reject_non_numbers() {
+        assert_eq!(eval(call("abs", vec![string("1")])), "Error: Invalid argument");
+        assert_eq!(eval(call("neg", vec![boolean(true)])), "Error: Invalid argument");
+        assert_eq!(eval(call("sign", vec![string("1")])), "Error: Invalid argument");
+    }
+
+    #[test]
+    fn arithmetic_overflow_is_an_error() {
+        assert_eq!(eval(call("mul", vec![num(i64::MAX), num(2)])), "Error: Integer overflow");
+        assert_eq!(eval(call("mul", vec![num(4_000_000_000), num(4_000_000_000)])), "Error: Integer overflow");
+        assert_eq!(eval(call("add", vec![num(i64::MAX), num(1)])), "Error: Integer overflow");
+        assert_eq!(eval(call("sub", vec![num(i64::MIN), num(1)])), "Error: Integer overflow");
+        assert_eq!(eval(call("pow", vec![num(2), num(63)])), "Error: Integer overflow");
+        assert_eq!(eval(call("pow", vec![num(2), num(62)])), "4611686018427387904");
+    }
+
+    #[test]
+    fn lists_nest_and_print_in_parentheses() {
+        assert_eq!(eval(list(vec![num(1), list(vec![num(2), num(3)]), list(vec![])])), "(1 (2 3) ())");
+        assert_eq!(eval(call("cons", vec![num(0), list(vec![num(1), num(2)])])), "(0 1 2)");
+    }
+
+    #[test]
+    fn car_and_cdr_split_a_list() {
+        let nested = list(vec![list(vec![num(1), num(2)]), num(3)]);
+        assert_eq!(eval(call("car", vec![nested.clone()])), "(1 2)");
+        assert_eq!(eval(call("cdr", vec![nested])), "(3)");
+        assert_eq!(eval(call("cdr", vec![list(vec![num(1)])])), "()");
+    }
+
+    #[test]
+    fn car_and_cdr_of_the_empty_list_are_errors() {
+        assert_eq!(eval(call("car", vec![list(vec![])])), "Error: car of empty list");
+        assert_eq!(eval(call("cdr", vec![list(vec![])])), "Error: cdr of empty list");
+    }
+
+    #[test]
+    fn map_applies_a_lambda_or_builtin_to_each_element() {
+        let double = lambda(&["n"], call("mul", vec![ident("n"), num(2)]));
+        assert_eq!(eval(call("map", vec![double, list(vec![num(1), num(2), num(3)])])), "(2 4 6)");
+        assert_eq!(eval(call("map", vec![ident("abs"), list(vec![num(-1), num(2)])])), "(1 2)");
+        assert_eq!(eval(call("map", vec![ident("abs"), list(vec![])])), "()");
+    }
+
+    #[test]
+    fn map_needs_a_function_and_a_list() {
+        let message = "Error: map expects a function and a list";
+        assert_eq!(eval(call("map", vec![num(1), list(vec![num(1)])])), message);
+        assert_eq!(eval(call("map", vec![ident("abs"), num(1)])), message);
+    }
+
+    #[test]
+    fn fold_reduces_from_the_left() {
+        let numbers = list(vec![num(1), num(2), num(3), num(4)]);
+        assert_eq!(eval(call("fold", vec![ident("add"), num(0), numbers])), "10");
+        // ((10 - 1) - 2)
+        assert_eq!(eval(call("fold", vec![ident("sub"), num(10), list(vec![num(1), num(2)])])), "7");
+    }
+
+    #[test]
+    fn filter_keeps_elements_the_predicate_accepts() {
+        let numbers = list(vec![num(1), num(2), num(3), num(4)]);
+        let even = lambda(&["n"], call("zero?", vec![call("mod", vec![ident("n"), num(2)])]));
+        assert_eq!(eval(call("filter", vec![even, numbers])), "(2 4)");
+        let not_a_predicate = lambda(&["n"], ident("n"));
+        assert_eq!(
+            eval(call("filter", vec![not_a_predicate, list(vec![num(1)])])),
+            "Error: filter predicate must return a boolean"
+        );
+    }
+
+    #[test]
+    fn length_counts_list_elements_and_characters() {
+        assert_eq!(eval(call("length", vec![list(vec![num(1), num(2), num(3)])])), "3");
+        assert_eq!(eval(call("length", vec![list(vec![])])), "0");
+        // Unicode scalar values, not bytes
+        assert_eq!(eval(call("length", vec![string("héllo")])), "5");
+    }
+
+    #[test]
+    fn length_rejects_numbers_and_booleans() {
+        assert_eq!(eval(call("length", vec![num(5)])), "Error: length expects a list or string");
+        assert_eq!(eval(call("length", vec![boolean(true)])), "Error: length expects a list or string");
+    }
+
+    #[test]
+    fn defined_functions_can_call_themselves() {
+        let factorial = lambda(
+            &["n"],
+            if_expr(
+                call("zero?", vec![ident("n")]),
+                num(1),
+                call("mul", vec![ident("n"), call("fact", vec![call("sub", vec![ident("n"), num(1)])])]),
+            ),
+        );
+        assert_eq!(eval_program(vec![define("fact", factorial), call("fact", vec![num(10)])]), "3628800");
+    }
+
+    #[test]
+    fn let_shadows_only_inside_its_body() {
+        let inner = let_expr("x", num(1), call("add", vec![ident("x"), num(1)]));
+        assert_eq!(eval_program(vec![define("x", num(10)), inner.clone()]), "2");
+        assert_eq!(eval_program(vec![define("x", num(10)), inner, ident("x")]), "10");
+    }
+
+    #[test]
+    fn let_bindings_are_gone_after_the_body() {
+        let program = vec![let_expr("y", num(1), ident("y")), ident("y")];
+        assert_eq!(eval_program(program), "Error: Unbound variable: y");
+    }
+
+    #[test]
+    fn let_star_bindings_see_the_earlier_ones() {
+        let program = Expr::LetStar(
+            bindings(vec![("a", num(1)), ("b", call("add", vec![ident("a"), num(1)]))]),
+            Box::new(call("list", vec![ident("a"), ident("b")])),
+        );
+        assert_eq!(eval(program), "(1 2)");
+    }
+
+    #[test]
+    fn let_star_names_must_be_identifiers() {
+        let program = Expr::LetStar(vec![Expr::Clause(vec![num(1), num(2)])], Box::new(num(0)));
+        assert_eq!(eval(program), "Error: Invalid variable name");
+    }
+
+    #[test]
+    fn parallel_let_computes_every_value_before_binding() {
+        let swapped = bindings(vec![("x", ident("y")), ("y", ident("x"))]);
+        let body = Box::new(list(vec![ident("x"), ident("y")]));
+        let setup = || vec![define("x", num(1)), define("y", num(2))];
+
+        let mut parallel = setup();
+        parallel.push(Expr::LetMany(swapped.clone(), body.clone()));
+        assert_eq!(eval_program(parallel), "(2 1)");
+
+        // let* binds one at a time, so y sees the new x
+        let mut sequential = setup();
+        sequential.push(Expr::LetStar(swapped, body));
+        assert_eq!(eval_program(sequential), "(2 2)");
+    }
+
+    #[test]
+    fn set_updates_the_binding_in_a_parent_scope() {
+        let program = vec![define("x", num(1)), let_expr("y", num(0), set("x", num(5))), ident("x")];
+        assert_eq!(eval_program(program), "5");
+    }
+
+    #[test]
+    fn set_of_an_unbound_variable_is_an_error() {
+        assert_eq!(eval(set("nope", num(1))), "Error: Cannot set! unbound variable");
+    }
+
+    #[test]
+    fn while_loops_until_its_condition_is_false() {
+        let program = vec![
+            define("i", num(0)),
+            define("total", num(0)),
+            Expr::While(
+                Box::new(call("<", vec![ident("i"), num(5)])),
+                Box::new(begin(vec![
+                    set("total", call("add", vec![ident("total"), ident("i")])),
+                    set("i", call("add", vec![ident("i"), num(1)])),
+                    ident("i"),
+                ])),
+            ),
+        ];
+        // The loop's value is the body's last value
+        assert_eq!(eval_program(program.clone()), "5");
+        let mut total = program;
+        total.push(ident("total"));
+        assert_eq!(eval_program(total), "10");
+    }
+
+    #[test]
+    fn while_that_never_runs_is_nil_and_needs_a_boolean_condition() {
+        assert_eq!(eval(Expr::While(Box::new(boolean(false)), Box::new(num(1)))), "nil");
+        assert_eq!(
+            eval(Expr::While(Box::new(num(1)), Box::new(num(1)))),
+            "Error: While condition must evaluate to a boolean"
+        );
+    }
+
+    #[test]
+    fn begin_runs_in_order_and_returns_the_last_value() {
+        let program = vec![
+            define("x", num(1)),
+            begin(vec![set("x", num(2)), set("x", call("mul", vec![ident("x"), num(10)])), ident("x")]),
+        ];
+        assert_eq!(eval_program(program), "20");
+    }
+
+    #[test]
+    fn empty_begin_is_an_error() {
+        assert_eq!(eval(begin(vec![])), "Error: empty begin");
+    }
+
+    #[test]
+    fn variadic_builtins_take_any_number_of_arguments() {
+        assert_eq!(eval(call("add", vec![num(1), num(2), num(3), num(4)])), "10");
+        assert_eq!(eval(call("mul", vec![num(1), num(2), num(3), num(4)])), "24");
+        assert_eq!(eval(call("add", vec![])), "0");
+        assert_eq!(eval(call("mul", vec![num(7)])), "7");
+        assert_eq!(eval(list(vec![num(1), num(2), num(3), num(4), num(5)])), "(1 2 3 4 5)");
+    }
+
+    #[test]
+    fn min_and_max_are_variadic() {
+        assert_eq!(eval(call("max", vec![num(1), num(2), num(3)])), "3");
+        assert_eq!(eval(call("min", vec![num(4), num(-1), num(2)])), "-1");
+        assert_eq!(eval(call("min", vec![])), "Error: Expected at least 1 argument");
+    }
+
+    #[test]
+    fn errors_inside_a_lambda_report_the_lambda_body() {
+        let program = vec![
+            define("f", lambda(&["x"], call("add", vec![ident("x"), string("a")]))),
+            list(vec![num(0), call("f", vec![num(1)])]),
+        ];
+        let error = run(program).unwrap_err();
+        assert_eq!(error.message, "Invalid arguments");
+        assert_eq!(error.path, vec!["Application[2]", "Lambda body"]);
+    }
+
+    #[test]
+    fn a_local_variable_named_like_a_builtin_is_called_instead() {
+        let program = let_expr(
+            "add",
+            lambda(&["a", "b"], call("sub", vec![ident("a"), ident("b")])),
+            call("add", vec![num(5), num(3)]),
+        );
+        assert_eq!(eval(program), "2");
+        assert_eq!(eval(call("add", vec![num(5), num(3)])), "8");
+    }
+
+    #[test]
+    fn calling_an_unbound_name_is_an_error() {
+        assert_eq!(eval(call("nope", vec![num(1)])), "Error: Unbound function: nope");
+    }
+
+    #[test]
+    fn an_unbound_variable_is_an_error_rather_than_its_name() {
+        assert_eq!(eval(ident("undefined-thing")), "Error: Unbound variable: undefined-thing");
+    }
+
+    #[test]
+    fn recursion_a_thousand_calls_deep_completes() {
+        let count = lambda(
+            &["n"],
+            if_expr(
+                call("zero?", vec![ident("n")]),
+                num(0),
+                call("add", vec![num(1), call("count", vec![call("sub", vec![ident("n"), num(1)])])]),
+            ),
+        );
+        assert_eq!(eval_program(vec![define("count", count), call("count", vec![num(1000)])]), "1000");
+    }
+
+    #[test]
+    fn builtins_resolve_from_deeply_nested_scopes() {
+        let mut program = call("add", vec![ident("v0"), ident("v99")]);
+        for i in 0..100 {
+            program = let_expr(&format!("v{}", i), num(i), program);
+        }
+        assert_eq!(eval(program), "99");
+    }
+
+    #[test]
+    fn child_scopes_hold_no_builtins_of_their_own() {
+        let root = Rc::new(RefCell::new(Env::new(&[])));
+        let child = Env::with_parent(root.clone());
+        assert!(!root.borrow().builtins.is_empty());
+        assert!(child.borrow().builtins.is_empty());
+        assert!(child.borrow().lookup("add").is_some());
+    }
+
+    #[test]
+    fn tail_recursion_runs_in_constant_stack() {
+        let sum = lambda(
+            &["n", "total"],
+            if_expr(
+                call("zero?", vec![ident("n")]),
+                ident("total"),
+                call(
+                    "sum",
+                    vec![call("sub", vec![ident("n"), num(1)]), call("add", vec![ident("total"), ident("n")])],
+                ),
+            ),
+        );
+        let program = vec![define("sum", sum), call("sum", vec![num(100_000), num(0)])];
+        assert_eq!(eval_program(program), "5000050000");
+    }
+
+    #[test]
+    fn quote_returns_a_symbol_without_looking_it_up() {
+        assert_eq!(eval_program(vec![define("foo", num(1)), quote(ident("foo"))]), "foo");
+        assert_eq!(eval(call("string?", vec![quote(ident("foo"))])), "false");
+    }
+
+    #[test]
+    fn quoted_applications_round_trip_as_lists() {
+        let quoted = quote(call("add", vec![num(1), list(vec![string("a")])]));
+        assert_eq!(eval(quoted.clone()), r#"(add 1 (list "a"))"#);
+        assert_eq!(eval(call("length", vec![quoted])), "3");
+    }
+
+    #[test]
+    fn eval_evaluates_quoted_data() {
+        assert_eq!(eval(call("eval", vec![quote(call("add", vec![num(1), num(2)]))])), "3");
+        assert_eq!(eval_program(vec![define("x", num(5)), call("eval", vec![quote(ident("x"))])]), "5");
+    }
+
+    #[test]
+    fn eval_rejects_values_that_were_not_quoted() {
+        assert_eq!(eval(call("eval", vec![num(1)])), "Error: eval expects a quoted symbol or list");
+    }
+
+    #[test]
+    fn string_append_joins_all_its_arguments() {
+        assert_eq!(eval(call("string-append", vec![string("a"), string("b"), string("c")])), r#""abc""#);
+        assert_eq!(eval(call("string-append", vec![])), r#""""#);
+    }
+
+    #[test]
+    fn substring_indexes_by_character() {
+        assert_eq!(eval(call("substring", vec![string("héllo wörld"), num(1), num(4)])), r#""éll""#);
+        assert_eq!(eval(call("substring", vec![string("wörld"), num(0), num(5)])), r#""wörld""#);
+        assert_eq!(eval(call("substring", vec![string("abc"), num(1), num(1)])), r#""""#);
+    }
+
+    #[test]
+    fn substring_rejects_out_of_range_and_reversed_indices() {
+        let message = "Error: substring index out of range";
+        assert_eq!(eval(call("substring", vec![string("abc"), num(1), num(5)])), message);
+        assert_eq!(eval(call("substring", vec![string("abc"), num(2), num(1)])), message);
+        assert_eq!(eval(call("substring", vec![string("abc"), num(-1), num(2)])), message);
+    }
+
+    #[test]
+    fn string_to_number_parses_integers_and_decimals() {
+        assert_eq!(eval(call("string->number", vec![string("42")])), "42");
+        assert_eq!(eval(call("string->number", vec![string("-2.5")])), "-2.5");
+    }
+
+    #[test]
+    fn string_to_number_rejects_text() {
+        assert_eq!(eval(call("string->number", vec![string("abc")])), "Error: Not a number: abc");
+    }
+
+    #[test]
+    fn numbers_round_trip_through_strings() {
+        let round_trip = call("string->number", vec![call("number->string", vec![num(-17)])]);
+        assert_eq!(eval(round_trip), "-17");
+        assert_eq!(eval(call("number->string", vec![num(-17)])), r#""-17""#);
+    }
+
+    #[test]
+    fn case_conversion_is_unicode_aware() {
+        assert_eq!(eval(call("string-upcase", vec![string("ABC")])), r#""ABC""#);
+        assert_eq!(eval(call("string-upcase", vec![string("straße")])), r#""STRASSE""#);
+        assert_eq!(eval(call("string-downcase", vec![string("ÀBC")])), r#""àbc""#);
+    }
+
+    #[test]
+    fn string_trim_strips_surrounding_whitespace() {
+        assert_eq!(eval(call("string-trim", vec![string("  hi there \n")])), r#""hi there""#);
+    }
+
+    #[test]
+    fn string_builtins_reject_non_strings() {
+        assert_eq!(eval(call("string-upcase", vec![num(1)])), "Error: Expected a string");
+        assert_eq!(eval(call("string-trim", vec![boolean(true)])), "Error: Expected a string");
+    }
+
+    #[test]
+    fn string_split_keeps_empty_fields() {
+        assert_eq!(eval(call("string-split", vec![string("a,b,c"), string(",")])), r#"("a" "b" "c")"#);
+        assert_eq!(eval(call("string-split", vec![string("a,,b"), string(",")])), r#"("a" "" "b")"#);
+    }
+
+    #[test]
+    fn string_split_rejects_an_empty_separator() {
+        assert_eq!(eval(call("string-split", vec![string("abc"), string("")])), "Error: empty separator");
+    }
+
+    #[test]
+    fn numeric_predicates() {
+        assert_eq!(eval(call("even?", vec![num(0)])), "true");
+        assert_eq!(eval(call("even?", vec![num(-4)])), "true");
+        assert_eq!(eval(call("odd?", vec![num(-3)])), "true");
+        assert_eq!(eval(call("odd?", vec![num(2)])), "false");
+        assert_eq!(eval(call("positive?", vec![num(0)])), "false");
+        assert_eq!(eval(call("negative?", vec![num(0)])), "false");
+        assert_eq!(eval(call("negative?", vec![num(-1)])), "true");
+    }
+
+    #[test]
+    fn numeric_predicates_reject_non_numbers() {
+        assert_eq!(eval(call("even?", vec![string("2")])), "Error: Invalid argument");
+        assert_eq!(eval(call("negative?", vec![boolean(true)])), "Error: Invalid argument");
+    }
+
+    #[test]
+    fn type_predicates_report_each_kind_of_value() {
+        let values = [num(1), string("a"), boolean(true), ident("abs"), lambda(&["x"], ident("x"))];
+        let expected = [
+            ("number?", [true, false, false, false, false]),
+            ("string?", [false, true, false, false, false]),
+            ("bool?", [false, false, true, false, false]),
+            ("function?", [false, false, false, true, true]),
+        ];
+        for (predicate, answers) in expected {
+            for (value, answer) in values.iter().zip(answers) {
+                assert_eq!(eval(call(predicate, vec![value.clone()])), answer.to_string(), "{} {:?}", predicate, value);
+            }
+        }
+    }
+
+    #[test]
+    fn apply_spreads_a_list_into_arguments() {
+        assert_eq!(eval(call("apply", vec![ident("add"), list(vec![num(3), num(4)])])), "7");
+        let subtract = lambda(&["a", "b"], call("sub", vec![ident("a"), ident("b")]));
+        assert_eq!(eval(call("apply", vec![subtract, list(vec![num(5), num(3)])])), "2");
+    }
+
+    #[test]
+    fn apply_enforces_arity_like_a_direct_call() {
+        let program = call("apply", vec![lambda(&["a", "b"], ident("a")), list(vec![num(1)])]);
+        assert_eq!(eval(program), "Error: expected 2 arguments, got 1");
+        assert_eq!(eval(call("apply", vec![ident("add"), num(1)])), "Error: apply expects a function and a list");
+    }
+
+    #[test]
+    fn compose_applies_the_second_function_first() {
+        let neg_of_abs = call("compose", vec![ident("neg"), ident("abs")]);
+        assert_eq!(eval(call_value(neg_of_abs.clone(), vec![num(-5)])), "-5");
+        assert_eq!(eval(call_value(neg_of_abs, vec![num(5)])), "-5");
+        let abs_of_neg = call("compose", vec![ident("abs"), ident("neg")]);
+        assert_eq!(eval(call_value(abs_of_neg, vec![num(3)])), "3");
+    }
+
+    #[test]
+    fn compose_defers_arity_errors_until_the_call() {
+        let composed = call("compose", vec![ident("sub"), ident("abs")]);
+        assert_eq!(eval(call_value(composed, vec![num(1)])), "Error: sub: expected 2 arguments, got 1");
+        assert_eq!(eval(call("compose", vec![num(1), ident("abs")])), "Error: compose expects two functions");
+    }
+
+    #[test]
+    fn letrec_bindings_can_call_each_other() {
+        // Each answers for n by asking the other about n - 1
+        let ask = |other: &str, at_zero| {
+            let previous = call("sub", vec![ident("n"), num(1)]);
+            lambda(&["n"], if_expr(call("zero?", vec![ident("n")]), boolean(at_zero), call(other, vec![previous])))
+        };
+        let functions = bindings(vec![("even", ask("odd", true)), ("odd", ask("even", false))]);
+        let letrec = |body| Expr::LetRec(functions.clone(), Box::new(body));
+        assert_eq!(eval(letrec(call("even", vec![num(10)]))), "true");
+        assert_eq!(eval(letrec(call("odd", vec![num(7)]))), "true");
+        assert_eq!(eval(letrec(call("even", vec![num(7)]))), "false");
+    }
+
+    #[test]
+    fn define_with_a_call_shaped_header_defines_a_function() {
+        let square = Expr::Define(
+            Box::new(call("square", vec![ident("n")])),
+            Box::new(call("mul", vec![ident("n"), ident("n")])),
+        );
+        assert_eq!(eval_program(vec![square, call("square", vec![num(7)])]), "49");
+    }
+
+    #[test]
+    fn pretty_printer_renders_s_expressions() {
+        let square = define("square", lambda(&["n"], call("mul", vec![ident("n"), ident("n")])));
+        assert_eq!(pretty_print_expr(&square, 0), "(define square\n  (lambda\n    (n)\n    (mul n n)))");
+        assert_eq!(pretty_print_expr(&call("square", vec![num(4)]), 0), "(square 4)");
+    }
+
+    #[test]
+    fn dividing_the_smallest_number_by_minus_one_is_an_error() {
+        assert_eq!(eval(call("div", vec![num(i64::MIN), num(-1)])), "Error: Arithmetic overflow");
+        assert_eq!(eval(call("rem", vec![num(i64::MIN), num(-1)])), "Error: Arithmetic overflow");
+        assert_eq!(eval(call("mod", vec![num(i64::MIN), num(-1)])), "Error: Arithmetic overflow");
+        assert_eq!(eval(call("div", vec![num(1), num(0)])), "Error: Division by zero");
+    }
+
+    #[test]
+    fn gcd_and_lcm_are_never_negative() {
+        assert_eq!(eval(call("gcd", vec![num(12), num(18)])), "6");
+        assert_eq!(eval(call("gcd", vec![num(8), num(15)])), "1");
+        assert_eq!(eval(call("gcd", vec![num(-12), num(18)])), "6");
+        assert_eq!(eval(call("lcm", vec![num(4), num(6)])), "12");
+        assert_eq!(eval(call("lcm", vec![num(-4), num(6)])), "12");
+    }
+
+    #[test]
+    fn gcd_and_lcm_with_zero() {
+        assert_eq!(eval(call("gcd", vec![num(0), num(0)])), "0");
+        assert_eq!(eval(call("gcd", vec![num(0), num(5)])), "5");
+        assert_eq!(eval(call("lcm", vec![num(0), num(5)])), "0");
+    }
+
+    #[test]
+    fn isqrt_rounds_down() {
+        assert_eq!(eval(call("isqrt", vec![num(49)])), "7");
+        assert_eq!(eval(call("isqrt", vec![num(50)])), "7");
+        assert_eq!(eval(call("isqrt", vec![num(0)])), "0");
+        assert_eq!(eval(call("isqrt", vec![num(i64::MAX)])), "3037000499");
+    }
+
+    #[test]
+    fn sqrt_gives_a_float() {
+        assert_eq!(eval(call("sqrt", vec![float(2.25)])), "1.5");
+        assert_eq!(eval(call("sqrt", vec![num(16)])), "4.0");
+    }
+
+    #[test]
+    fn square_roots_of_negatives_are_errors() {
+        assert_eq!(eval(call("isqrt", vec![num(-1)])), "Error: isqrt of negative");
+        assert_eq!(eval(call("sqrt", vec![num(-1)])), "Error: sqrt of negative");
+    }
+
+    #[test]
+    fn factorial_builtin() {
+        assert_eq!(eval(call("factorial", vec![num(0)])), "1");
+        assert_eq!(eval(call("factorial", vec![num(20)])), "2432902008176640000");
+        assert_eq!(eval(call("factorial", vec![num(21)])), "Error: Integer overflow");
+        assert_eq!(eval(call("factorial", vec![num(-1)])), "Error: factorial of negative");
+    }
+
+    #[test]
+    fn comparisons_chain_over_all_their_arguments() {
+        assert_eq!(eval(call("<", vec![num(1), num(2), num(3)])), "true");
+        assert_eq!(eval(call("<", vec![num(1), num(3), num(2)])), "false");
+        assert_eq!(eval(call(">", vec![num(3), num(2), num(1)])), "true");
+        assert_eq!(eval(call("leq", vec![num(1), num(1), num(2)])), "true");
+        assert_eq!(eval(call("geq", vec![num(2), num(2), num(3)])), "false");
+        assert_eq!(eval(call("<", vec![num(5)])), "true");
+    }
+
+    #[test]
+    fn comparisons_reject_non_numbers_and_no_arguments() {
+        assert_eq!(eval(call("<", vec![num(1), string("2")])), "Error: Invalid arguments");
+        assert_eq!(eval(call("<", vec![])), "Error: Expected at least 1 argument");
+    }
+
+    #[test]
+    fn when_and_unless_run_their_body_on_one_outcome() {
+        assert_eq!(eval(Expr::When(Box::new(boolean(true)), Box::new(num(1)))), "1");
+        assert_eq!(eval(Expr::When(Box::new(boolean(false)), Box::new(num(1)))), "nil");
+        assert_eq!(eval(Expr::Unless(Box::new(boolean(false)), Box::new(num(1)))), "1");
+        assert_eq!(eval(Expr::Unless(Box::new(boolean(true)), Box::new(num(1)))), "nil");
+    }
+
+    #[test]
+    fn when_and_unless_need_a_boolean_condition() {
+        let message = "Error: When condition must evaluate to a boolean";
+        assert_eq!(eval(Expr::When(Box::new(num(1)), Box::new(num(2)))), message);
+        let message = "Error: Unless condition must evaluate to a boolean";
+        assert_eq!(eval(Expr::Unless(Box::new(num(1)), Box::new(num(2)))), message);
+    }
+
+    #[test]
+    fn nil_is_distinct_from_zero() {
+        assert_eq!(eval(call("print", vec![])), "nil");
+        assert_eq!(eval(define("x", num(1))), "nil");
+        assert_eq!(eval(call("nil?", vec![ident("nil")])), "true");
+        assert_eq!(eval(call("nil?", vec![num(0)])), "false");
+    }
+
+    #[test]
+    fn bitwise_operations() {
+        assert_eq!(eval(call("bit-and", vec![num(12), num(10)])), "8");
+        assert_eq!(eval(call("bit-or", vec![num(12), num(10)])), "14");
+        assert_eq!(eval(call("bit-xor", vec![num(12), num(10)])), "6");
+        assert_eq!(eval(call("bit-not", vec![num(0)])), "-1");
+        assert_eq!(eval(call("shift-left", vec![num(1), num(4)])), "16");
+        assert_eq!(eval(call("shift-right", vec![num(-16), num(2)])), "-4");
+    }
+
+    #[test]
+    fn masks_and_shifts_combine() {
+        let middle_byte = call("bit-and", vec![call("shift-right", vec![num(0xABCD), num(4)]), num(0xFF)]);
+        assert_eq!(eval(middle_byte), "188");
+    }
+
+    #[test]
+    fn shifts_out_of_range_are_errors() {
+        assert_eq!(eval(call("shift-left", vec![num(1), num(64)])), "Error: invalid shift amount");
+        assert_eq!(eval(call("shift-right", vec![num(1), num(-1)])), "Error: invalid shift amount");
+    }
+
+    #[test]
+    fn if_let_binds_a_value_that_is_not_nil() {
+        let program = Expr::IfLet(
+            boxed("x"),
+            Box::new(num(5)),
+            Box::new(call("add", vec![ident("x"), num(1)])),
+            Box::new(num(0)),
+        );
+        assert_eq!(eval(program), "6");
+    }
+
+    #[test]
+    fn if_let_takes_the_else_branch_for_nil() {
+        let program = Expr::IfLet(boxed("found"), boxed("nil"), boxed("found"), Box::new(string("none")));
+        assert_eq!(eval(program.clone()), r#""none""#);
+        assert_eq!(eval_program(vec![program, ident("found")]), "Error: Unbound variable: found");
+    }
+
+    #[test]
+    fn defined_checks_for_a_binding_without_evaluating() {
+        let defined = |name| Expr::Defined(boxed(name));
+        assert_eq!(eval_program(vec![define("y", num(1)), defined("y")]), "true");
+        assert_eq!(eval(defined("y")), "false");
+        assert_eq!(eval(defined("add")), "true");
+        assert_eq!(eval(Expr::Defined(Box::new(num(1)))), "Error: defined? expects an identifier");
+    }
+
+    #[test]
+    fn string_ref_returns_a_character() {
+        assert_eq!(eval(call("string-ref", vec![string("héllo"), num(1)])), "é");
+        assert_eq!(eval(call("string?", vec![call("string-ref", vec![string("a"), num(0)])])), "false");
+        assert_eq!(eval(call("string-ref", vec![string("abc"), num(3)])), "Error: string-ref index out of range");
+    }
+
+    #[test]
+    fn chars_convert_to_and_from_code_points() {
+        assert_eq!(eval(call("char->number", vec![call("string-ref", vec![string("é"), num(0)])])), "233");
+        assert_eq!(eval(call("number->char", vec![num(955)])), "λ");
+        assert_eq!(eval(call("number->char", vec![num(0xD800)])), "Error: Invalid code point: 55296");
+        assert_eq!(eval(call("number->char", vec![num(-1)])), "Error: Invalid code point: -1");
+    }
+
+    #[test]
+    fn range_counts_up_or_down_excluding_the_end() {
+        assert_eq!(eval(call("range", vec![num(0), num(5)])), "(0 1 2 3 4)");
+        assert_eq!(eval(call("range", vec![num(5), num(0), num(-2)])), "(5 3 1)");
+        assert_eq!(eval(call("range", vec![num(3), num(3)])), "()");
+        assert_eq!(eval(call("range", vec![num(5), num(0)])), "()");
+    }
+
+    #[test]
+    fn range_rejects_a_zero_step() {
+        assert_eq!(eval(call("range", vec![num(0), num(5), num(0)])), "Error: range step must not be zero");
+    }
+
+    #[test]
+    fn nth_indexes_from_zero() {
+        let items = list(vec![num(1), num(2), num(3)]);
+        assert_eq!(eval(call("nth", vec![items.clone(), num(1)])), "2");
+        assert_eq!(eval(call("list-ref", vec![items.clone(), num(0)])), "1");
+        assert_eq!(eval(call("nth", vec![items.clone(), num(3)])), "Error: list index out of range");
+        assert_eq!(eval(call("nth", vec![items, num(-1)])), "Error: list index out of range");
+    }
+
+    #[test]
+    fn append_and_reverse() {
+        let appended = call("append", vec![list(vec![num(1)]), list(vec![num(2), num(3)]), list(vec![])]);
+        assert_eq!(eval(appended), "(1 2 3)");
+        assert_eq!(eval(call("append", vec![list(vec![num(1)]), num(2)])), "Error: append expects lists");
+        assert_eq!(eval(call("reverse", vec![list(vec![num(1), num(2), num(3)])])), "(3 2 1)");
+    }
+
+    #[test]
+    fn number_literals_accept_radix_prefixes() {
+        assert_eq!(eval(number_literal("0xFF")), "255");
+        assert_eq!(eval(number_literal("0b101")), "5");
+        assert_eq!(eval(number_literal("0o17")), "15");
+        assert_eq!(eval(number_literal("-42")), "-42");
+        assert_eq!(eval(number_literal("-0x10")), "-16");
+    }
+
+    #[test]
+    fn malformed_number_literals_are_errors() {
+        for literal in ["0x", "12a", "0x-1", ""] {
+            assert_eq!(eval(number_literal(literal)), "Error: invalid number literal", "{:?}", literal);
+        }
+    }
+
+    #[test]
+    fn do_loops_over_a_half_open_range() {
+        let program = vec![
+            define("total", num(0)),
+            Expr::Do(
+                boxed("i"),
+                Box::new(num(0)),
+                Box::new(num(5)),
+                Box::new(set("total", call("add", vec![ident("total"), ident("i")]))),
+            ),
+            ident("total"),
+        ];
+        assert_eq!(eval_program(program), "10");
+    }
+
+    #[test]
+    fn the_do_variable_is_local_to_the_loop() {
+        let program = vec![Expr::Do(boxed("k"), Box::new(num(0)), Box::new(num(3)), boxed("k")), ident("k")];
+        assert_eq!(eval_program(program), "Error: Unbound variable: k");
+    }
+
+    #[test]
+    fn gensym_never_repeats() {
+        let fresh = || call("gensym", vec![]);
+        assert_ne!(eval(fresh()), eval(fresh()));
+        assert!(eval(call("gensym", vec![string("tmp")])).starts_with("tmp"));
+        assert_eq!(eval(call("gensym", vec![num(1)])), "Error: gensym prefix must be a string");
+    }
+
+    #[test]
+    fn assert_passes_silently_or_fails_with_its_message() {
+        assert_eq!(eval(call("assert", vec![boolean(true)])), "nil");
+        assert_eq!(eval(call("assert", vec![boolean(false), string("broken")])), "Error: broken");
+        assert_eq!(eval(call("assert", vec![boolean(false)])), "Error: Assertion failed");
+        assert_eq!(eval(call("assert", vec![num(1)])), "Error: assert expects a boolean and an optional message");
+    }
+
+    #[test]
+    fn error_raises_its_message() {
+        assert_eq!(eval(call("error", vec![string("boom")])), "Error: boom");
+        assert_eq!(eval(call("error", vec![num(1)])), "Error: error expects a string");
+    }
+
+    #[test]
+    fn try_recovers_from_errors_with_the_message_bound() {
+        let failing = Box::new(call("div", vec![num(1), num(0)]));
+        assert_eq!(eval(Expr::Try(failing.clone(), Box::new(num(0)))), "0");
+        assert_eq!(eval(Expr::Try(failing, boxed("error-message"))), r#""Division by zero""#);
+        assert_eq!(eval(Expr::Try(Box::new(num(5)), Box::new(num(0)))), "5");
+    }
+
+    #[test]
+    fn maps_are_persistent() {
+        let with_a = call("map-set", vec![call("make-map", vec![]), string("a"), num(1)]);
+        let program = vec![
+            define("m", with_a),
+            define("m2", call("map-set", vec![ident("m"), string("b"), num(2)])),
+            list(vec![
+                call("map-keys", vec![ident("m")]),
+                call("map-keys", vec![ident("m2")]),
+                call("map-get", vec![ident("m2"), string("b")]),
+                call("map-get", vec![ident("m"), string("b")]),
+            ]),
+        ];
+        assert_eq!(eval_program(program), r#"(("a") ("a" "b") 2 nil)"#);
+    }
+
+    #[test]
+    fn map_builtins_reject_other_values() {
+        assert_eq!(eval(call("map-get", vec![num(1), string("a")])), "Error: map-get expects a map and a string key");
+        assert_eq!(eval(call("map-keys", vec![list(vec![])])), "Error: map-keys expects a map");
+    }
+
+    #[test]
+    fn equal_question_compares_structure() {
+        let nested = |last| list(vec![num(1), list(vec![num(2), num(last)])]);
+        assert_eq!(eval(call("equal?", vec![nested(3), nested(3)])), "true");
+        assert_eq!(eval(call("equal?", vec![nested(3), nested(4)])), "false");
+        assert_eq!(eval(call("equal?", vec![string("a"), string("a")])), "true");
+        assert_eq!(eval(call("equal?", vec![num(1), string("1")])), "false");
+        assert_eq!(eval(call("equal?", vec![ident("nil"), num(0)])), "false");
+    }
+
+    #[test]
+    fn functions_are_equal_only_to_themselves() {
+        assert_eq!(eval(call("equal?", vec![ident("abs"), ident("abs")])), "true");
+        assert_eq!(eval(call("equal?", vec![ident("abs"), ident("neg")])), "false");
+        let identity = || lambda(&["a"], ident("a"));
+        let program = vec![define("f", identity()), call("equal?", vec![ident("f"), ident("f")])];
+        assert_eq!(eval_program(program), "true");
+        assert_eq!(eval(call("equal?", vec![identity(), identity()])), "false");
+        let composed = || call("compose", vec![ident("abs"), ident("neg")]);
+        let program = vec![define("g", composed()), call("equal?", vec![ident("g"), ident("g")])];
+        assert_eq!(eval_program(program), "true");
+        assert_eq!(eval(call("equal?", vec![composed(), composed()])), "false");
+        for wrapped in [call("memoize", vec![ident("abs")]), call("curry", vec![ident("add"), num(1)])] {
+            let program = vec![define("h", wrapped), call("equal?", vec![ident("h"), ident("h")])];
+            assert_eq!(eval_program(program), "true");
+        }
+    }
+
+    #[test]
+    fn cons_onto_a_non_list_makes_a_pair() {
+        assert_eq!(eval(call("cons", vec![num(1), num(2)])), "(1 . 2)");
+        assert_eq!(eval(call("cons", vec![num(1), call("cons", vec![num(2), num(3)])])), "(1 2 . 3)");
+        assert_eq!(eval(call("car", vec![call("cons", vec![num(1), num(2)])])), "1");
+        assert_eq!(eval(call("cdr", vec![call("cons", vec![num(1), num(2)])])), "2");
+    }
+
+    #[test]
+    fn cons_onto_a_list_stays_a_list() {
+        let built = call("cons", vec![num(1), call("cons", vec![num(2), list(vec![])])]);
+        assert_eq!(eval(built), "(1 2)");
+        assert_eq!(eval(call("pair?", vec![call("cons", vec![num(1), num(2)])])), "true");
+        assert_eq!(eval(call("pair?", vec![list(vec![])])), "false");
+    }
+
+    #[test]
+    fn let_value_sees_the_outer_binding_it_shadows() {
+        let program = vec![define("x", num(10)), let_expr("x", call("add", vec![ident("x"), num(1)]), ident("x"))];
+        assert_eq!(eval_program(program), "11");
+    }
+
+    #[test]
+    fn time_returns_the_value_of_its_expression() {
+        assert_eq!(eval(Expr::Time(Box::new(call("add", vec![num(1), num(2)])))), "3");
+    }
+
+    #[test]
+    fn memoize_calls_the_function_once_per_argument() {
+        let counted_fib = lambda(
+            &["n"],
+            begin(vec![
+                set("calls", call("add", vec![ident("calls"), num(1)])),
+                if_expr(
+                    call("<", vec![ident("n"), num(2)]),
+                    ident("n"),
+                    call(
+                        "add",
+                        vec![
+                            call("fib", vec![call("sub", vec![ident("n"), num(1)])]),
+                            call("fib", vec![call("sub", vec![ident("n"), num(2)])]),
+                        ],
+                    ),
+                ),
+            ]),
+        );
+        let program = vec![
+            define("calls", num(0)),
+            define("fib", call("memoize", vec![counted_fib])),
+            list(vec![call("fib", vec![num(25)]), ident("calls")]),
+        ];
+        assert_eq!(eval_program(program), "(75025 26)");
+        assert_eq!(eval(call("memoize", vec![num(1)])), "Error: memoize expects a function");
+    }
+
+    #[test]
+    fn cond_clauses_run_every_body_expression() {
+        let program = vec![
+            define("x", num(0)),
+            cond(vec![vec![boolean(true), set("x", num(5)), call("add", vec![ident("x"), num(1)])]]),
+        ];
+        assert_eq!(eval_program(program), "6");
+    }
+
+    #[test]
+    fn substring_predicates() {
+        assert_eq!(eval(call("string-contains", vec![string("haystack"), string("st")])), "true");
+        assert_eq!(eval(call("string-contains", vec![string("abc"), string("x")])), "false");
+        assert_eq!(eval(call("starts-with", vec![string("abc"), string("ab")])), "true");
+        assert_eq!(eval(call("starts-with", vec![string("abc"), string("bc")])), "false");
+        assert_eq!(eval(call("ends-with", vec![string("abc"), string("bc")])), "true");
+        assert_eq!(eval(call("ends-with", vec![string("abc"), string("ab")])), "false");
+    }
+
+    #[test]
+    fn every_string_contains_the_empty_string() {
+        for predicate in ["string-contains", "starts-with", "ends-with"] {
+            assert_eq!(eval(call(predicate, vec![string("abc"), string("")])), "true", "{}", predicate);
+        }
+        assert_eq!(eval(call("string-contains", vec![num(1), string("")])), "Error: Expected a string");
+    }
+
+    #[test]
+    fn format_fills_placeholders_in_order() {
+        let formatted = call("format", vec![string("{} + {} = {}"), num(1), num(2), num(3)]);
+        assert_eq!(eval(formatted), r#""1 + 2 = 3""#);
+        assert_eq!(eval(call("format", vec![string("{{}} {}"), string("x")])), r#""{} x""#);
+    }
+
+    #[test]
+    fn format_needs_one_argument_per_placeholder() {
+        let too_few = call("format", vec![string("{} {}"), num(1)]);
+        assert_eq!(eval(too_few), "Error: Too few arguments for format string");
+        let too_many = call("format", vec![string("{}"), num(1), num(2)]);
+        assert_eq!(eval(too_many), "Error: Too many arguments for format string");
+        assert_eq!(eval(call("format", vec![string("{")])), "Error: Unmatched brace in format string");
+    }
+
+    #[test]
+    fn validation_accepts_well_formed_programs() {
+        let program = define("f", lambda(&["x"], if_expr(boolean(true), ident("x"), num(0))));
+        assert_eq!(validate_expr(&program), Ok(()));
+    }
+
+    #[test]
+    fn validation_reports_every_structural_error() {
+        let program = begin(vec![
+            Expr::Lambda(vec![Expr::Parameters(vec![])]),
+            Expr::Let(Box::new(num(1)), Box::new(num(2)), Box::new(num(3))),
+            // Not evaluated, so the unbound name is fine
+            ident("unbound"),
+        ]);
+        let errors = vec!["Lambda must have exactly 2 expressions".to_string(), "Invalid variable name".to_string()];
+        assert_eq!(validate_expr(&program), Err(errors));
+    }
+
+    #[test]
+    fn dump_lists_visible_bindings_sorted_by_name() {
+        let root = Rc::new(RefCell::new(Env::new(&[])));
+        for (name, value) in [("b", string("two\nlines")), ("a", num(1)), ("c", num(3))] {
+            eval_expr(define(name, value), &root).unwrap();
+        }
+        let child = Env::with_parent(root);
+        child.borrow_mut().insert_vars("c".to_string(), ResultValue::Number(30));
+        assert_eq!(child.borrow().dump(), "a = 1\nb = \"two\\nlines\"\nc = 30");
+    }
+
+    #[test]
+    fn a_clause_with_only_a_test_returns_the_test_value() {
+        assert_eq!(eval(cond(vec![vec![boolean(false)], vec![boolean(true)]])), "true");
+        assert_eq!(eval(cond(vec![vec![boolean(false)], vec![ident("else"), num(2)]])), "2");
+        assert_eq!(eval(cond(vec![vec![ident("nil")], vec![call("add", vec![num(1), num(2)])]])), "3");
+        assert_eq!(eval(cond(vec![vec![list(vec![num(1), num(2)])]])), "(1 2)");
+        let empty_clause = Expr::Cond(vec![Expr::Clause(vec![])]);
+        assert_eq!(eval(empty_clause), "Error: Each clause must have at least 1 expression");
+    }
+
+    #[test]
+    fn curry_fixes_the_leading_arguments() {
+        assert_eq!(eval(call_value(call("curry", vec![ident("add"), num(1)]), vec![num(2)])), "3");
+        assert_eq!(eval(call_value(call("curry", vec![ident("sub"), num(10)]), vec![num(3)])), "7");
+        let three = lambda(&["a", "b", "c"], list(vec![ident("a"), ident("b"), ident("c")]));
+        assert_eq!(eval(call_value(call("curry", vec![three, num(1), num(2)]), vec![num(3)])), "(1 2 3)");
+    }
+
+    #[test]
+    fn curried_functions_check_arity_when_called() {
+        let too_many = call_value(call("curry", vec![ident("sub"), num(1), num(2)]), vec![num(3)]);
+        assert_eq!(eval(too_many), "Error: sub: expected 2 arguments, got 3");
+        assert_eq!(eval(call("curry", vec![num(1), num(2)])), "Error: curry expects a function");
+    }
+
+    #[test]
+    fn case_matches_a_constant_or_a_list_of_constants() {
+        let describe = |n| {
+            case(
+                num(n),
+                vec![
+                    vec![num(1), string("one")],
+                    vec![Expr::Application(vec![num(2), num(3), num(4)]), string("a few")],
+                    vec![ident("else"), string("many")],
+                ],
+            )
+        };
+        assert_eq!(eval(describe(1)), r#""one""#);
+        assert_eq!(eval(describe(3)), r#""a few""#);
+        assert_eq!(eval(describe(9)), r#""many""#);
+    }
+
+    #[test]
+    fn case_without_a_match_is_nil() {
+        assert_eq!(eval(case(string("b"), vec![vec![string("a"), num(1)]])), "nil");
+    }
+
+    #[test]
+    fn case_key_errors_are_reported_under_the_key() {
+        let program = case(call("car", vec![num(1)]), vec![vec![ident("else"), num(1)]]);
+        let error = run(vec![program]).unwrap_err();
+        assert_eq!(error.message, "Invalid argument");
+        assert_eq!(error.path, vec!["Case key"]);
+    }
+
+    #[test]
+    fn arity_errors_name_the_function_and_the_counts() {
+        assert_eq!(eval(call("sub", vec![num(1), num(2), num(3)])), "Error: sub: expected 2 arguments, got 3");
+        let program = vec![define("f", lambda(&["a"], ident("a"))), call("f", vec![num(1), num(2)])];
+        assert_eq!(eval_program(program), "Error: f: expected 1 argument, got 2");
+    }
+
+    #[test]
+    fn arity_errors_keep_the_name_through_higher_order_builtins() {
+        let items = list(vec![num(1), num(2)]);
+        assert_eq!(eval(call("map", vec![ident("sub"), items.clone()])), "Error: sub: expected 2 arguments, got 1");
+        let program = vec![define("f", lambda(&["a", "b"], ident("a"))), call("map", vec![ident("f"), items.clone()])];
+        assert_eq!(eval_program(program), "Error: f: expected 2 arguments, got 1");
+        let program = vec![define("g", lambda(&["a"], ident("a"))), call("apply", vec![ident("g"), items])];
+        assert_eq!(eval_program(program), "Error: g: expected 1 argument, got 2");
+        // A lambda with no binding of its own is named after the parameter it's called through
+        let call_twice = lambda(&["h"], call("h", vec![num(1), num(2)]));
+        assert_eq!(eval(call_value(call_twice, vec![lambda(&["a"], ident("a"))])), "Error: h: expected 1 argument, got 2");
+    }
+
+    #[test]
+    fn rationals_stay_exact_and_normalized() {
+        assert_eq!(eval(call("add", vec![rational(1, 3), rational(1, 6)])), "1/2");
+        assert_eq!(eval(rational(2, 4)), "1/2");
+        assert_eq!(eval(rational(1, -2)), "-1/2");
+        assert_eq!(eval(rational(4, 2)), "2");
+        assert_eq!(eval(call("mul", vec![rational(2, 3), num(3)])), "2");
+        assert_eq!(eval(rational(1, 0)), "Error: Zero denominator");
+    }
+
+    #[test]
+    fn rationals_compare_by_value() {
+        assert_eq!(eval(call("equal", vec![rational(1, 2), rational(2, 4)])), "true");
+        assert_eq!(eval(call("!=", vec![rational(1, 3), rational(2, 6)])), "false");
+    }
+
+    #[test]
+    fn scope_keeps_its_definitions_to_itself() {
+        let scoped = Expr::Scope(vec![define("y", num(1)), call("add", vec![ident("y"), num(1)])]);
+        assert_eq!(eval(scoped.clone()), "2");
+        assert_eq!(eval_program(vec![scoped, ident("y")]), "Error: Unbound variable: y");
+    }
+
+    #[test]
+    fn restore_undoes_definitions_and_assignments_since_the_snapshot() {
+        let env = Rc::new(RefCell::new(Env::new(&[])));
+        eval_expr(define("p", num(1)), &env).unwrap();
+        let snapshot = env.borrow().snapshot();
+        eval_expr(set("p", num(2)), &env).unwrap();
+        eval_expr(define("q", num(3)), &env).unwrap();
+        env.borrow_mut().restore(snapshot);
+        assert_eq!(eval_expr(ident("p"), &env).unwrap().to_string(), "1");
+        assert!(eval_expr(ident("q"), &env).is_err());
+    }
+
+    #[test]
+    fn for_each_returns_nil() {
+        assert_eq!(eval(call("for-each", vec![ident("abs"), list(vec![num(-1)])])), "nil");
+        assert_eq!(eval(call("for-each", vec![ident("abs"), num(1)])), "Error: for-each expects a function and a list");
+    }
+
+    #[test]
+    fn strings_convert_to_character_lists_and_back() {
+        assert_eq!(eval(call("string->list", vec![string("abc")])), "(a b c)");
+        assert_eq!(eval(call("length", vec![call("string->list", vec![string("日本")])])), "2");
+        let round_trip = call("list->string", vec![call("string->list", vec![string("héllo")])]);
+        assert_eq!(eval(round_trip), r#""héllo""#);
+        let mixed = list(vec![string("ab"), call("string-ref", vec![string("c"), num(0)])]);
+        assert_eq!(eval(call("list->string", vec![mixed])), r#""abc""#);
+    }
+
+    #[test]
+    fn list_to_string_rejects_other_elements() {
+        let numbers = list(vec![num(1)]);
+        assert_eq!(eval(call("list->string", vec![numbers])), "Error: list->string expects chars or strings");
+    }
+
+    #[test]
+    fn sort_orders_numbers() {
+        assert_eq!(eval(call("sort", vec![list(vec![num(3), num(-1), num(2)])])), "(-1 2 3)");
+        assert_eq!(eval(call("sort", vec![list(vec![num(1), string("a")])])), "Error: sort expects a list of numbers");
+    }
+
+    #[test]
+    fn sort_by_is_stable() {
+        let pair = |n, s| list(vec![num(n), string(s)]);
+        let by_number = lambda(
+            &["a", "b"],
+            call("<", vec![call("car", vec![ident("a")]), call("car", vec![ident("b")])]),
+        );
+        let sorted = call("sort-by", vec![list(vec![pair(1, "a"), pair(0, "b"), pair(1, "c")]), by_number]);
+        assert_eq!(eval(sorted), r#"((0 "b") (1 "a") (1 "c"))"#);
+    }
+
+    #[test]
+    fn take_and_drop_clamp_to_the_list_length() {
+        let items = || list(vec![num(1), num(2), num(3)]);
+        assert_eq!(eval(call("take", vec![items(), num(2)])), "(1 2)");
+        assert_eq!(eval(call("take", vec![items(), num(5)])), "(1 2 3)");
+        assert_eq!(eval(call("drop", vec![items(), num(1)])), "(2 3)");
+        assert_eq!(eval(call("drop", vec![items(), num(5)])), "()");
+    }
+
+    #[test]
+    fn take_and_drop_reject_negative_counts() {
+        let items = || list(vec![num(1)]);
+        assert_eq!(eval(call("take", vec![items(), num(-1)])), "Error: take count must not be negative");
+        assert_eq!(eval(call("drop", vec![items(), num(-1)])), "Error: drop count must not be negative");
+    }
+
+    #[test]
+    fn zip_pairs_elements_up_to_the_shorter_list() {
+        let zipped = call("zip", vec![list(vec![num(1), num(2)]), list(vec![string("a"), string("b")])]);
+        assert_eq!(eval(zipped), r#"((1 "a") (2 "b"))"#);
+        let uneven = call("zip", vec![list(vec![num(1), num(2), num(3)]), list(vec![num(4)])]);
+        assert_eq!(eval(uneven), "((1 4))");
+        assert_eq!(eval(call("zip", vec![num(1), list(vec![])])), "Error: zip expects two lists");
+    }
+
+    #[test]
+    fn written_strings_are_quoted_and_escaped() {
+        assert_eq!(eval(string("say \"hi\"\nbye")), r#""say \"hi\"\nbye""#);
+        assert_eq!(eval(list(vec![string("a b"), string("\\")])), r#"("a b" "\\")"#);
+    }
+
+    #[test]
+    fn displayed_strings_are_raw() {
+        let value = ResultValue::String("say \"hi\"".to_string());
+        assert_eq!(value.to_string(), "say \"hi\"");
+    }
+
+    #[test]
+    fn arrow_clauses_pass_the_test_value_to_a_function() {
+        let double = lambda(&["x"], call("mul", vec![ident("x"), num(2)]));
+        assert_eq!(eval(cond(vec![vec![num(21), ident("=>"), double]])), "42");
+        let skipped = cond(vec![vec![boolean(false), ident("=>"), ident("abs")], vec![ident("else"), num(0)]]);
+        assert_eq!(eval(skipped), "0");
+    }
+
+    #[test]
+    fn arrow_clauses_call_in_tail_position() {
+        // (define (count-down n) (cond ((zero? n) 0) ((sub n 1) => count-down)))
+        let count_down = lambda(
+            &["n"],
+            cond(vec![
+                vec![call("zero?", vec![ident("n")]), num(0)],
+                vec![call("sub", vec![ident("n"), num(1)]), ident("=>"), ident("count-down")],
+            ]),
+        );
+        let program = vec![define("count-down", count_down), call("count-down", vec![num(100_000)])];
+        assert_eq!(eval_program(program), "0");
+        let program = cond(vec![vec![num(1), ident("=>"), lambda(&["n"], call("car", vec![ident("n")]))]]);
+        let error = run(vec![program]).unwrap_err();
+        assert_eq!(error.message, "Invalid argument");
+        assert_eq!(error.path, vec!["Cond clause 1", "Lambda body"]);
+    }
+
+    #[test]
+    fn and_and_or_return_the_deciding_value() {
+        assert_eq!(eval(Expr::Or(vec![boolean(false), num(5)])), "5");
+        assert_eq!(eval(Expr::And(vec![num(1), num(2), num(3)])), "3");
+        assert_eq!(eval(Expr::And(vec![num(1), boolean(false), num(3)])), "false");
+        assert_eq!(eval(Expr::Or(vec![boolean(false), ident("nil")])), "nil");
+        assert_eq!(eval(Expr::And(vec![])), "true");
+        assert_eq!(eval(Expr::Or(vec![])), "false");
+    }
+
+    #[test]
+    fn true_false_and_nil_are_constants() {
+        assert_eq!(eval(ident("true")), "true");
+        assert_eq!(eval(ident("false")), "false");
+        assert_eq!(eval(ident("nil")), "nil");
+        assert_eq!(eval(set("true", ident("false"))), "Error: Cannot rebind constant: true");
+        assert_eq!(eval(define("nil", num(3))), "Error: Cannot rebind constant: nil");
+        assert_eq!(eval(Expr::Assignment(boxed("false"), Box::new(num(1)))), "Error: Cannot rebind constant: false");
+        // They aren't variables, so they don't show up among the bindings
+        assert_eq!(Env::new(&[]).dump(), "");
+    }
+
+    #[test]
+    fn comparisons_mix_floats_and_rationals() {
+        assert_eq!(eval(call("<", vec![num(1), float(1.5), num(2)])), "true");
+        assert_eq!(eval(call("<", vec![rational(1, 3), float(0.5), num(1)])), "true");
+        assert_eq!(eval(call("geq", vec![rational(1, 2), float(0.5)])), "true");
+        assert_eq!(eval(call(">", vec![rational(2, 3), rational(1, 2)])), "true");
+    }
+
+    #[test]
+    fn equal_and_not_equal_compare_floats_by_value() {
+        assert_eq!(eval(call("equal", vec![float(1.5), float(1.5)])), "true");
+        assert_eq!(eval(call("equal", vec![num(1), float(1.0)])), "true");
+        assert_eq!(eval(call("!=", vec![float(1.5), num(2)])), "true");
+        assert_eq!(eval(call("equal", vec![rational(1, 2), float(0.5)])), "true");
+    }
+
+    #[test]
+    fn string_to_number_rejects_non_finite_values() {
+        assert_eq!(eval(call("string->number", vec![string("NaN")])), "Error: Not a number: NaN");
+        assert_eq!(eval(call("string->number", vec![string("inf")])), "Error: Not a number: inf");
+    }
+
+    #[test]
+    fn forms_that_finish_in_a_subexpression_are_tail_position_forms() {
+        let forms = vec![
+            Expr::If(boxed("c"), boxed("a"), boxed("b")),
+            Expr::Cond(vec![Expr::Clause(vec![ident("c"), ident("a")])]),
+            Expr::Case(boxed("k"), vec![Expr::Clause(vec![ident("else"), ident("a")])]),
+            Expr::Block(vec![ident("a")]),
+            Expr::Begin(vec![ident("a")]),
+            Expr::Scope(vec![ident("a")]),
+            Expr::Let(boxed("x"), boxed("a"), boxed("x")),
+            Expr::LetStar(vec![], boxed("a")),
+            Expr::LetMany(vec![], boxed("a")),
+            Expr::LetRec(vec![], boxed("a")),
+            Expr::IfLet(boxed("x"), boxed("a"), boxed("x"), boxed("b")),
+            Expr::When(boxed("c"), boxed("a")),
+            Expr::Unless(boxed("c"), boxed("a")),
+            Expr::Try(boxed("a"), boxed("b")),
+        ];
+        for expr in forms {
+            assert!(is_tail_position(&expr), "{:?}", expr);
+        }
+    }
+
+    #[test]
+    fn other_expressions_are_not_tail_position_forms() {
+        let expressions = vec![
+            // A call continues in the lambda's body, which isn't part of the call expression
+            Expr::Application(vec![ident("f"), ident("a")]),
+            ident("a"),
+            Expr::Clause(vec![ident("a")]),
+            Expr::Number(1),
+            Expr::NumberStr("0x1".to_string()),
+            Expr::Float(1.5),
+            Expr::Bool(true),
+            Expr::String("a".to_string()),
+            Expr::Parameters(vec![ident("a")]),
+            Expr::Lambda(vec![Expr::Parameters(vec![]), ident("a")]),
+            Expr::Define(boxed("x"), boxed("a")),
+            Expr::Assignment(boxed("x"), boxed("a")),
+            Expr::And(vec![ident("a")]),
+            Expr::Or(vec![ident("a")]),
+            Expr::Set(boxed("x"), boxed("a")),
+            Expr::While(boxed("c"), boxed("a")),
+            Expr::Quote(boxed("a")),
+            Expr::Defined(boxed("a")),
+            Expr::Do(boxed("i"), boxed("a"), boxed("b"), boxed("c")),
+            Expr::Time(boxed("a")),
+            Expr::DumpEnv,
+        ];
+        for expr in expressions {
+            assert!(!is_tail_position(&expr), "{:?}", expr);
+        }
+    }
+
+    #[test]
+    fn closures_share_the_state_they_capture() {
+        // make-counter returns an incrementing and a reading closure over the same n
+        let make_counter = lambda(
+            &[],
+            let_expr(
+                "n",
+                num(0),
+                list(vec![
+                    lambda(&[], begin(vec![set("n", call("add", vec![ident("n"), num(1)])), ident("n")])),
+                    lambda(&[], ident("n")),
+                ]),
+            ),
+        );
+        let program = vec![
+            define("make-counter", make_counter),
+            define("counter", call("make-counter", vec![])),
+            define("increment", call("car", vec![ident("counter")])),
+            define("read", call("car", vec![call("cdr", vec![ident("counter")])])),
+            define("other", call("car", vec![call("make-counter", vec![])])),
+            call("increment", vec![]),
+            call("increment", vec![]),
+            call("other", vec![]),
+            list(vec![call("read", vec![]), call("other", vec![])]),
+        ];
+        assert_eq!(eval_program(program), "(2 2)");
+    }
+
+    #[test]
+    fn json_floats_tag_nan_and_infinities_as_strings() {
+        assert_eq!(ResultValue::Float(1.5).to_json(), serde_json::json!({ "Float": 1.5 }));
+        assert_eq!(ResultValue::Float(f64::NAN).to_json(), serde_json::json!({ "Float": "NaN" }));
+        assert_eq!(ResultValue::Float(f64::INFINITY).to_json(), serde_json::json!({ "Float": "inf" }));
+        assert_eq!(ResultValue::Float(f64::NEG_INFINITY).to_json(), serde_json::json!({ "Float": "-inf" }));
+    }
+}
@@ -1,419 +1,147 @@
-use serde_derive::Deserialize;
+use interpreter::{eval_expr, pretty_print_expr, read_stdin_line, validate_expr, Env, EvalError, Expr, ResultValue, SharedEnv, Written};
 use std::{
-    collections::HashMap, io::{self, Read}
+    cell::RefCell,
+    io::{self, Read},
+    rc::Rc,
+    sync::atomic::{AtomicBool, Ordering},
 };
 
-
-#[derive(Debug, Deserialize, Clone)]
-#[serde(rename_all = "PascalCase")]
-enum Expr {
-    Application(Vec<Expr>),
-    Identifier(String),
-    Cond(Vec<Expr>),
-    Block(Vec<Expr>),
-    Clause(Vec<Expr>),
-    Number(i64),
-    String(String),
-    Parameters(Vec<Expr>),
-    Lambda(Vec<Expr>),
-    Let(Box<Expr>, Box<Expr>, Box<Expr>),
-    Define(Box<Expr>, Box<Expr>),
-    Assignment(Box<Expr>, Box<Expr>),
-}
-
-
-
-#[derive(Debug, Clone)]
-enum ResultValue {
-    Number(i64),
-    Bool(bool),
-    String(String),
-    Func(usize, fn(Vec<ResultValue>) -> Result<ResultValue, String>),
-    Lambda(Vec<String>, Box<Expr>, Env),
-}
-
-impl std::fmt::Display for ResultValue {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            ResultValue::Number(n) => write!(f, "{}", n),
-            ResultValue::Bool(b) => write!(f, "{}", b),
-            ResultValue::String(s) => write!(f, "{}", s),
-            ResultValue::Func(_, _) => write!(f, "<function>"),
-            ResultValue::Lambda(p, b, _) => write!(f, "<lambda {:?} {:?}>", p, b),
+// Frames shown at each end of a long error path; runaway recursion leaves thousands in between
+const PATH_FRAMES_SHOWN: usize = 8;
+
+// Print results as structured JSON (`--json`) instead of their display form
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+fn print_result(result: Result<ResultValue, EvalError>) {
+    match result {
+        Ok(result) if JSON_OUTPUT.load(Ordering::Relaxed) => println!("{}", result.to_json()),
+        Ok(result) => println!("{}", Written(&result)),
+        Err(e) => {
+            eprintln!("Error: {:?}", e.message);
+            if e.path.len() > 2 * PATH_FRAMES_SHOWN {
+                let hidden = e.path.len() - 2 * PATH_FRAMES_SHOWN;
+                let (first, last) = (&e.path[..PATH_FRAMES_SHOWN], &e.path[PATH_FRAMES_SHOWN + hidden..]);
+                eprintln!("  at {} > ... {} more > {}", first.join(" > "), hidden, last.join(" > "));
+            } else if !e.path.is_empty() {
+                eprintln!("  at {}", e.path.join(" > "));
+            }
         }
     }
 }
 
-#[derive(Debug, Clone)]
-struct Env {
-    vars: HashMap<String, ResultValue>,
-    builtins: HashMap<String, ResultValue>,
+// Evaluates one JSON expression per line against the same environment until EOF
+fn run_repl(env: &SharedEnv) {
+    while let Some(line) = read_stdin_line().expect("Failed to read input") {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<Expr>(&line) {
+            Ok(expr) => print_result(eval_expr(expr, env)),
+            Err(e) => eprintln!("Error: \"JSON was not well-formatted: {}\"", e),
+        }
+    }
 }
 
-impl Env {
-    fn new() -> Self {
-        let mut vars = HashMap::new();
-        // Initialize the environment with Roman numerals
-        vars.insert("x".to_string(), ResultValue::Number(10));
-        vars.insert("v".to_string(), ResultValue::Number(5));
-        vars.insert("i".to_string(), ResultValue::Number(1));
-
-        // Initialize the environment with built-in functions
-        let mut builtins = HashMap::new();
-        builtins.insert(
-            "add".to_string(),
-            ResultValue::Func(2, |args| {
-                if args.len() != 2 {
-                    return Err("Expected exactly 2 arguments".to_string());
-                }
-
-                match (args[0].clone(), args[1].clone()) {
-                    (ResultValue::Number(a), ResultValue::Number(b)) => Ok(ResultValue::Number(a + b)),
-                    _ => Err("Invalid arguments".to_string()),
-                }
-            }),
-        );
-        builtins.insert(
-            "sub".to_string(),
-            ResultValue::Func(2, |args| {
-                if args.len() != 2 {
-                    return Err("Expected exactly 2 arguments".to_string());
-                }
-
-                match (args[0].clone(), args[1].clone()) {
-                    (ResultValue::Number(a), ResultValue::Number(b)) => Ok(ResultValue::Number(a - b)),
-                    _ => Err("Invalid arguments".to_string()),
-                }
-            }),
-        );
-        builtins.insert(
-            "mul".to_string(),
-            ResultValue::Func(2, |args| {
-                if args.len() != 2 {
-                    return Err("Expected exactly 2 arguments".to_string());
-                }
-
-                match (args[0].clone(), args[1].clone()) {
-                    (ResultValue::Number(a), ResultValue::Number(b)) => Ok(ResultValue::Number(a * b)),
-                    _ => Err("Invalid arguments".to_string()),
-                }
-            }),
-        );
-        builtins.insert(
-            "div".to_string(),
-            ResultValue::Func(2, |args| {
-                if args.len() != 2 {
-                    return Err("Expected exactly 2 arguments".to_string());
-                }
-
-                match (args[0].clone(), args[1].clone()) {
-                    (ResultValue::Number(a), ResultValue::Number(b)) => {
-                        if b == 0 {
-                            Err("Division by zero".to_string())
-                        } else {
-                            Ok(ResultValue::Number(a / b))
-                        }
-                    }
-                    _ => Err("Invalid arguments".to_string()),
-                }
-            }),
-        );
-        builtins.insert(
-            "pow".to_string(),
-            ResultValue::Func(2, |args| {
-                if args.len() != 2 {
-                    return Err("Expected exactly 2 arguments".to_string());
-                }
-
-                match (args[0].clone(), args[1].clone()) {
-                    (ResultValue::Number(a), ResultValue::Number(b)) => Ok(ResultValue::Number(a.pow(b as u32))),
-                    _ => Err("Invalid arguments".to_string()),
-                }
-            }),
-        );
-        builtins.insert(
-            "zero?".to_string(),
-            ResultValue::Func(1, |args| {
-                if args.len() != 1 {
-                    return Err("Expected exactly 1 argument".to_string());
-                }
-
-                match args[0].clone() {
-                    ResultValue::Number(n) => Ok(ResultValue::Bool(n == 0)),
-                    _ => Err("Invalid argument".to_string()),
-                }
-            }),
-        );
-        builtins.insert(
-            "equal".to_string(),
-            ResultValue::Func(2, |args| {
-                if args.len() != 2 {
-                    return Err("Expected exactly 2 arguments".to_string());
-                }
-
-                match (args[0].clone(), args[1].clone()) {
-                    (ResultValue::Number(a), ResultValue::Number(b)) => Ok(ResultValue::Bool(a == b)),
-                    _ => Err("Invalid arguments".to_string()),
-                }
-            }),
-        );
-        builtins.insert(
-            "<".to_string(),
-            ResultValue::Func(2, |args| {
-                if args.len() != 2 {
-                    return Err("Expected exactly 2 arguments".to_string());
-                }
-
-                match (args[0].clone(), args[1].clone()) {
-                    (ResultValue::Number(a), ResultValue::Number(b)) => Ok(ResultValue::Bool(a < b)),
-                    _ => Err("Invalid arguments".to_string()),
-                }
-            }),
-        );
-        builtins.insert(
-            ">".to_string(),
-            ResultValue::Func(2, |args| {
-                if args.len() != 2 {
-                    return Err("Expected exactly 2 arguments".to_string());
-                }
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
 
-                match (args[0].clone(), args[1].clone()) {
-                    (ResultValue::Number(a), ResultValue::Number(b)) => Ok(ResultValue::Bool(a > b)),
-                    _ => Err("Invalid arguments".to_string()),
-                }
-            }),
-        );
-        builtins.insert(
-            "geq".to_string(),
-            ResultValue::Func(2, |args| {
-                if args.len() != 2 {
-                    return Err("Expected exactly 2 arguments".to_string());
+    for arg in &args {
+        if let Some(limit) = arg.strip_prefix("--max-depth=") {
+            match limit.parse::<usize>() {
+                Ok(limit) if limit > 0 => interpreter::set_max_depth(limit),
+                _ => {
+                    eprintln!("Error: \"Invalid --max-depth: {} (expected a positive number)\"", limit);
+                    std::process::exit(1);
                 }
+            }
+        }
+    }
 
-                match (args[0].clone(), args[1].clone()) {
-                    (ResultValue::Number(a), ResultValue::Number(b)) => Ok(ResultValue::Bool(a >= b)),
-                    _ => Err("Invalid arguments".to_string()),
-                }
-            }),
-        );
-        builtins.insert(
-            "leq".to_string(),
-            ResultValue::Func(2, |args| {
-                if args.len() != 2 {
-                    return Err("Expected exactly 2 arguments".to_string());
-                }
+    // The interpreter thread's stack is sized for the depth limit, so raising it with --max-depth
+    // still ends runaway recursion in a clean error; a limit too big for the system fails here
+    if let Err(e) = interpreter::with_eval_stack(move || run(args)) {
+        eprintln!("Error: \"Failed to start the interpreter thread: {}\"", e);
+        std::process::exit(1);
+    }
+}
 
-                match (args[0].clone(), args[1].clone()) {
-                    (ResultValue::Number(a), ResultValue::Number(b)) => Ok(ResultValue::Bool(a <= b)),
-                    _ => Err("Invalid arguments".to_string()),
-                }
-            }),
-        );
-        builtins.insert(
-            "print".to_string(),
-            ResultValue::Func(1, |args| {
-                if args.len() != 1 {
-                    return Err("Expected exactly 1 argument".to_string());
-                }
+fn run(args: Vec<String>) {
+    // Simulating the environment being initialized
+    let env = Rc::new(RefCell::new(Env::new(&[])));
 
-                println!("{}", args[0]);
-                Ok(ResultValue::Number(0))
-            }),
-        );
-        
+    // Flags start with `--`; the first other argument is the path of the program to run
+    let path = args.iter().find(|arg| !arg.starts_with("--"));
 
-        Self { vars, builtins }
+    if args.iter().any(|arg| arg == "--trace") || std::env::var("TRACE").is_ok_and(|value| value == "1") {
+        interpreter::set_trace(true);
     }
 
-    fn get_vars(&self, name: &str) -> Option<ResultValue> {
-        self.vars.get(name).cloned()
+    if args.iter().any(|arg| arg == "--lexical") {
+        interpreter::set_lexical_scope(true);
     }
-
-    fn insert_vars(&mut self, name: String, value: ResultValue) {
-        self.vars.insert(name, value);
+    if args.iter().any(|arg| arg == "--dynamic") {
+        interpreter::set_lexical_scope(false);
     }
 
-    fn update_vars(&mut self, name: &str, value: ResultValue) -> Result<(), String> {
-        if self.vars.contains_key(name) {
-            self.vars.insert(name.to_string(), value);
-            Ok(())
-        } else {
-            Err("Unbound identifier".to_string())
-        }
+    // The Roman numeral variables x, v and i are only bound on request
+    if args.iter().any(|arg| arg == "--preset") {
+        let mut env = env.borrow_mut();
+        env.insert_vars("x".to_string(), ResultValue::Number(10));
+        env.insert_vars("v".to_string(), ResultValue::Number(5));
+        env.insert_vars("i".to_string(), ResultValue::Number(1));
     }
-}
 
-fn eval_expr(expr: Expr, env: &mut Env) -> Result<ResultValue, String> {
-    // // backtrace for debugging
-    // println!("{:?}", expr);
+    if args.iter().any(|arg| arg == "--json") {
+        JSON_OUTPUT.store(true, Ordering::Relaxed);
+    }
 
-    match expr {
-        Expr::Number(n) => Ok(ResultValue::Number(n)),
-        Expr::String(s) => Ok(ResultValue::String(s)),
+    if args.iter().any(|arg| arg == "--repl") {
+        run_repl(&env);
+        return;
+    }
 
-        Expr::Application(mut args) => {
-            let func = eval_expr(args.remove(0), env)?;
-            if env.builtins.contains_key(&func.to_string()) {
-                return apply_function(env.builtins[&func.to_string()].clone(), args, env);
+    // Read input from the given file, or from stdin when no path is given
+    let mut input = String::new();
+    match path {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(contents) => input = contents,
+            Err(e) => {
+                eprintln!("Error: \"Cannot read {}: {}\"", path, e);
+                std::process::exit(1);
             }
-            apply_function(func, args, env)
-        }
-
-        Expr::Identifier(value) => match env.get_vars(&value) {
-            Some(val) => Ok(val),
-            None => Ok(ResultValue::String(value)),
         },
-
-        Expr::Block(exprs) => {
-            let mut result = ResultValue::Number(0);
-            for expr in exprs {
-                result = eval_expr(expr, env)?;
-            }
-            Ok(result)
-        }
-
-        Expr::Cond(clauses) => {
-            for clause in clauses {
-                match clause {
-                    Expr::Clause(mut clause) => {
-                        if clause.len() != 2 {
-                            return Err("Each clause must have exactly 2 expressions".to_string());
-                        }
-                        let cond = eval_expr(clause.remove(0), env)?;
-                        if cond.to_string() == "true" {
-                            return eval_expr(clause.remove(0), env);
-                        } else {
-                            clause.remove(0); // Remove the second expression if condition is false
-                        }
-                    }
-                    _ => return Err("Invalid clause".to_string()),
-                }
-            }
-            Err("No true clause".to_string())
-        }
-
-        Expr::Clause(_) => Err("Invalid clause not wrapped in a cond".to_string()),
-
-        Expr::Parameters(_) => Err("Invalid parameters not wrapped in a lambda".to_string()),
-
-        Expr::Lambda(mut args) => {
-            if args.len() != 2 {
-                return Err("Lambda must have exactly 2 expressions".to_string());
-            }
-            let params = args.remove(0);
-            let body_expr = args.remove(0);
-            let param_names = if let Expr::Parameters(params) = params {
-                params.into_iter().map(|param| {
-                    if let Expr::Identifier(name) = param {
-                        Ok(name)
-                    } else {
-                        Err("Invalid parameter".to_string())
-                    }
-                }).collect::<Result<Vec<_>, _>>()?
-            } else {
-                return Err("Invalid parameters".to_string());
-            };
-            Ok(ResultValue::Lambda(param_names, Box::new(body_expr), env.clone()))
-        }
-
-        Expr::Let(name, value, body) => {
-            let name = if let Expr::Identifier(name) = *name {
-                name
-            } else {
-                return Err("Invalid variable name".to_string());
-            };
-            let value = eval_expr(*value, env)?;
-            env.insert_vars(name, value);
-            eval_expr(*body, env)
-        }
-
-        Expr::Define(name, value) => {
-            let name = if let Expr::Identifier(name) = *name {
-                name
-            } else {
-                return Err("Invalid variable name".to_string());
-            };
-            let value = eval_expr(*value, env)?;
-
-            env.insert_vars(name, value);
-            Ok(ResultValue::Number(0))
-        }
-
-        Expr::Assignment(name, value) => {
-            let name = if let Expr::Identifier(name) = *name {
-                name
-            } else {
-                return Err("Invalid variable name".to_string());
-            };
-            let value = eval_expr(*value, env)?;
-            env.update_vars(&name, value.clone())?;
-            Ok(value)
+        None => {
+            io::stdin()
+                .read_to_string(&mut input)
+                .expect("Failed to read input");
         }
     }
-}
-
-fn apply_function(f: ResultValue, args: Vec<Expr>, env: &mut Env) -> Result<ResultValue, String> {
-    match f {
-        ResultValue::Func(args_length, func) => {
-            if args.len() != args_length {
-                return Err(format!("Expected {} arguments", args_length));
-            }
 
-            let arg_values = args
-                .into_iter()
-                .map(|arg| eval_expr(arg, env))
-                .collect::<Result<Vec<_>, _>>()?;
-
-            func(arg_values)
+    // println!("{}", input);
+    // Parse the input as JSON: either one expression or an array of top-level expressions
+    let exprs: Vec<Expr> = if input.trim_start().starts_with('[') {
+        serde_json::from_str(&input).expect("JSON was not well-formatted")
+    } else {
+        vec![serde_json::from_str(&input).expect("JSON was not well-formatted")]
+    };
+
+    if args.iter().any(|arg| arg == "--check") {
+        let errors: Vec<String> = exprs.iter().filter_map(|expr| validate_expr(expr).err()).flatten().collect();
+        for error in &errors {
+            eprintln!("Error: {:?}", error);
         }
-        ResultValue::Lambda(param_names, body, mut lambda_env) => {
-            if args.len() != param_names.len() {
-                return Err(format!("Expected {} arguments", param_names.len()));
-            }
-
-            // Evaluate arguments and extend the environment
-            for (param_name, arg) in param_names.into_iter().zip(args.into_iter()) {
-                let arg_value = eval_expr(arg, env)?;
-                if use_lexical_scope() {
-                    lambda_env.insert_vars(param_name, arg_value);
-                } else {
-                    env.insert_vars(param_name, arg_value);
-                }
-            }
+        std::process::exit(if errors.is_empty() { 0 } else { 1 });
+    }
 
-            // Evaluate the body of the lambda in the extended environment (lexical scope)
-            if use_lexical_scope() {
-                eval_expr(*body, &mut lambda_env)
-            } else {
-                eval_expr(*body, env)
-            }
+    if args.iter().any(|arg| arg == "--dump-ast") {
+        for expr in &exprs {
+            println!("{}", pretty_print_expr(expr, 0));
         }
-        _ => Err("Not a function".to_string()),
     }
-}
 
-fn use_lexical_scope() -> bool {
-    // Change this to true for lexical scope, false for dynamic scope
-    false
+    // Evaluate the expressions in order against the same environment, printing the last value
+    let result = exprs
+        .into_iter()
+        .try_fold(ResultValue::Nil, |_, expr| eval_expr(expr, &env));
+    print_result(result);
 }
-
-fn main() {
-    // Simulating the environment being initialized
-    let mut env = Env::new();
-
-    // Read input from stdin
-    let mut input = String::new();
-    io::stdin()
-        .read_to_string(&mut input)
-        .expect("Failed to read input");
-
-    // println!("{}", input);
-    // Parse the input as JSON
-    let expr: Expr = serde_json::from_str(&input).expect("JSON was not well-formatted");
-
-    // Evaluate the expression
-    match eval_expr(expr, &mut env) {
-        Ok(result) => println!("{}", result),
-        Err(e) => eprintln!("Error: {:?}", e),
-    }
-}
\ No newline at end of file
@@ -0,0 +1,370 @@
+use serde_json::json;
+use std::{
+    io::Write,
+    process::{Command, Output, Stdio},
+};
+
+// Runs the interpreter binary with the given arguments, feeding it `input` on stdin
+fn run(args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_Interpreter"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("the interpreter binary should start");
+    // A binary that rejects its arguments exits without reading, closing the pipe early
+    let _ = child.stdin.take().unwrap().write_all(input.as_bytes());
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+// Writes `contents` to a file in the temp directory, named for this process and the test
+fn temp_file(name: &str, contents: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("interpreter-{}-{}.json", std::process::id(), name));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+#[test]
+fn begin_runs_prints_in_order_before_its_value() {
+    let program = json!({"Begin": [
+        {"Application": [{"Identifier": "print"}, {"String": "first "}]},
+        {"Application": [{"Identifier": "print"}, {"String": "second "}]},
+        {"Number": 3}
+    ]});
+    assert_eq!(stdout(&run(&[], &program.to_string())), "first second 3\n");
+}
+
+#[test]
+fn repl_keeps_definitions_between_lines_and_survives_errors() {
+    let lines = [
+        json!({"Define": [{"Identifier": "x"}, {"Number": 5}]}),
+        json!({"Application": [{"Identifier": "add"}, {"Identifier": "x"}, {"Number": 1}]}),
+        json!({"Identifier": "y"}),
+        json!({"Identifier": "x"}),
+    ];
+    let input: String = lines.iter().map(|line| format!("{}\n", line)).collect();
+    let output = run(&["--repl"], &input);
+    assert_eq!(stdout(&output), "nil\n6\n5\n");
+    assert_eq!(stderr(&output), "Error: \"Unbound variable: y\"\n");
+}
+
+#[test]
+fn a_top_level_array_runs_in_order_and_prints_the_last_value() {
+    let program = json!([
+        {"Define": [{"Identifier": "x"}, {"Number": 20}]},
+        {"Application": [{"Identifier": "add"}, {"Identifier": "x"}, {"Number": 22}]}
+    ]);
+    assert_eq!(stdout(&run(&[], &program.to_string())), "42\n");
+}
+
+#[test]
+fn a_single_expression_still_runs() {
+    assert_eq!(stdout(&run(&[], r#"{"Number": 7}"#)), "7\n");
+}
+
+#[test]
+fn a_program_is_read_from_a_file_path() {
+    let path = temp_file("file-path", r#"{"Application": [{"Identifier": "mul"}, {"Number": 6}, {"Number": 7}]}"#);
+    let output = run(&[path.to_str().unwrap()], "");
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(stdout(&output), "42\n");
+}
+
+#[test]
+fn an_unreadable_file_is_a_clean_error() {
+    let output = run(&["/nonexistent/program.json"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).starts_with("Error: \"Cannot read /nonexistent/program.json"), "{}", stderr(&output));
+}
+
+#[test]
+fn trace_logs_each_step_to_stderr_indented_by_depth() {
+    let program = json!({"Application": [
+        {"Identifier": "add"},
+        {"Number": 1},
+        {"Application": [{"Identifier": "mul"}, {"Number": 2}, {"Number": 3}]}
+    ]});
+    let output = run(&["--trace"], &program.to_string());
+    assert_eq!(stdout(&output), "7\n");
+    let trace = stderr(&output);
+    assert!(trace.starts_with("-> Application("), "{}", trace);
+    assert!(trace.contains("\n  -> Application([Identifier(\"mul\")"), "{}", trace);
+    assert!(trace.contains("\n    <- 3\n  <- 6\n<- 7\n"), "{}", trace);
+
+    // Without the flag nothing is logged
+    assert_eq!(stderr(&run(&[], &program.to_string())), "");
+}
+
+// let amt = 1, incr = (lambda (n) (add amt n)) in let amt = 100 in incr(5): lexical scope sees the
+// amt incr was defined with, dynamic scope the one in force where it's called
+fn free_variable_program() -> String {
+    json!({"Let": [
+        {"Identifier": "amt"}, {"Number": 1},
+        {"Let": [
+            {"Identifier": "incr"},
+            {"Lambda": [
+                {"Parameters": [{"Identifier": "n"}]},
+                {"Application": [{"Identifier": "add"}, {"Identifier": "amt"}, {"Identifier": "n"}]}
+            ]},
+            {"Let": [
+                {"Identifier": "amt"}, {"Number": 100},
+                {"Application": [{"Identifier": "incr"}, {"Number": 5}]}
+            ]}
+        ]}
+    ]})
+    .to_string()
+}
+
+#[test]
+fn lexical_scope_resolves_free_variables_where_the_lambda_was_defined() {
+    assert_eq!(stdout(&run(&["--lexical"], &free_variable_program())), "6\n");
+    assert_eq!(stdout(&run(&[], &free_variable_program())), "6\n");
+}
+
+#[test]
+fn dynamic_scope_resolves_free_variables_where_the_lambda_is_called() {
+    assert_eq!(stdout(&run(&["--dynamic"], &free_variable_program())), "105\n");
+}
+
+#[test]
+fn json_output_is_structured() {
+    let program = json!({"Application": [
+        {"Identifier": "list"}, {"Number": 1}, {"String": "a"}, {"Bool": true},
+        {"Application": [{"Identifier": "list"}]}
+    ]});
+    let output = run(&["--json"], &program.to_string());
+    assert_eq!(stdout(&output), "{\"List\":[{\"Number\":1},{\"String\":\"a\"},{\"Bool\":true},{\"List\":[]}]}\n");
+}
+
+#[test]
+fn dump_ast_prints_the_program_before_its_value() {
+    let program = json!([
+        {"Define": [{"Identifier": "x"}, {"Number": 4}]},
+        {"Application": [{"Identifier": "mul"}, {"Identifier": "x"}, {"Number": 2}]}
+    ]);
+    assert_eq!(stdout(&run(&["--dump-ast"], &program.to_string())), "(define x 4)\n(mul x 2)\n8\n");
+}
+
+#[test]
+fn print_separates_values_with_spaces_and_println_ends_the_line() {
+    let program = json!([
+        {"Application": [{"Identifier": "print"}, {"Number": 1}, {"Number": 2}, {"Number": 3}]},
+        {"Application": [{"Identifier": "println"}]},
+        {"Application": [{"Identifier": "println"}, {"String": "a"}, {"Number": 1}]},
+        {"Number": 0}
+    ]);
+    assert_eq!(stdout(&run(&[], &program.to_string())), "1 2 3\na 1\n0\n");
+}
+
+#[test]
+fn lexical_closures_share_the_state_they_capture() {
+    let call = |name: &str| json!({"Application": [{"Identifier": name}]});
+    let car = |list| json!({"Application": [{"Identifier": "car"}, list]});
+    let n = || json!({"Identifier": "n"});
+    let increment = json!({"Begin": [
+        {"Set": [n(), {"Application": [{"Identifier": "add"}, n(), {"Number": 1}]}]},
+        n()
+    ]});
+    let make_counter = json!({"Lambda": [{"Parameters": []}, {"Let": [
+        n(), {"Number": 0},
+        {"Application": [
+            {"Identifier": "list"},
+            {"Lambda": [{"Parameters": []}, increment]},
+            {"Lambda": [{"Parameters": []}, n()]}
+        ]}
+    ]}]});
+    let define = |name: &str, value| json!({"Define": [{"Identifier": name}, value]});
+    let program = json!([
+        define("make-counter", make_counter),
+        define("counter", call("make-counter")),
+        define("increment", car(json!({"Identifier": "counter"}))),
+        define("read", car(json!({"Application": [{"Identifier": "cdr"}, {"Identifier": "counter"}]}))),
+        define("other", car(call("make-counter"))),
+        call("increment"),
+        call("increment"),
+        call("other"),
+        {"Application": [{"Identifier": "list"}, call("read"), call("other")]}
+    ]);
+    assert_eq!(stdout(&run(&["--lexical"], &program.to_string())), "(2 2)\n");
+}
+
+// (define loop (lambda (n) (add 1 (loop n)))) (loop 0): recursion with no base case
+fn runaway_recursion() -> String {
+    json!([
+        {"Define": [
+            {"Identifier": "loop"},
+            {"Lambda": [
+                {"Parameters": [{"Identifier": "n"}]},
+                {"Application": [
+                    {"Identifier": "add"}, {"Number": 1},
+                    {"Application": [{"Identifier": "loop"}, {"Identifier": "n"}]}
+                ]}
+            ]}
+        ]},
+        {"Application": [{"Identifier": "loop"}, {"Number": 0}]}
+    ])
+    .to_string()
+}
+
+#[test]
+fn runaway_recursion_is_a_clean_error() {
+    for args in [&[][..], &["--max-depth=100"]] {
+        let output = run(args, &runaway_recursion());
+        assert!(stderr(&output).starts_with("Error: \"Maximum recursion depth exceeded\"\n  at "));
+    }
+}
+
+#[test]
+fn read_line_and_read_number_take_input_from_stdin() {
+    let program = json!({"Application": [
+        {"Identifier": "list"},
+        {"Application": [{"Identifier": "read-line"}]},
+        {"Application": [{"Identifier": "read-number"}]},
+        {"Application": [{"Identifier": "read-number"}]},
+        {"Application": [{"Identifier": "read-line"}]}
+    ]});
+    // The program comes from a file so that stdin is free for its input
+    let path = temp_file("read-input", &program.to_string());
+    let output = run(&[path.to_str().unwrap()], "hello world\n42\n 2.5 \n");
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(stdout(&output), "(\"hello world\" 42 2.5 nil)\n");
+}
+
+#[test]
+fn read_number_rejects_text() {
+    let program = json!({"Application": [{"Identifier": "read-number"}]}).to_string();
+    let path = temp_file("read-text", &program);
+    let output = run(&[path.to_str().unwrap()], "abc\n");
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(stderr(&output), "Error: \"Not a number: abc\"\n");
+}
+
+#[test]
+fn time_reports_the_elapsed_time_on_stderr() {
+    let output = run(&[], r#"{"Time": {"Application": [{"Identifier": "add"}, {"Number": 1}, {"Number": 2}]}}"#);
+    assert_eq!(stdout(&output), "3\n");
+    assert!(stderr(&output).starts_with("time: "), "{}", stderr(&output));
+}
+
+#[test]
+fn check_validates_without_running() {
+    let valid = json!({"Application": [{"Identifier": "print"}, {"String": "ran"}]});
+    let output = run(&["--check"], &valid.to_string());
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "");
+
+    let invalid = json!([valid, {"Begin": []}]);
+    let output = run(&["--check"], &invalid.to_string());
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "");
+    assert_eq!(stderr(&output), "Error: \"empty begin\"\n");
+}
+
+#[test]
+fn dump_env_prints_each_binding_on_its_own_line() {
+    let program = json!([
+        {"Define": [{"Identifier": "b"}, {"String": "two"}]},
+        {"Define": [{"Identifier": "a"}, {"Number": 1}]},
+        "DumpEnv"
+    ]);
+    assert_eq!(stdout(&run(&[], &program.to_string())), "a = 1\nb = \"two\"\nnil\n");
+}
+
+#[test]
+fn for_each_runs_its_function_for_the_side_effects() {
+    let program = json!({"Application": [
+        {"Identifier": "for-each"},
+        {"Identifier": "println"},
+        {"Application": [{"Identifier": "list"}, {"Number": 1}, {"Number": 2}, {"Number": 3}]}
+    ]});
+    assert_eq!(stdout(&run(&[], &program.to_string())), "1\n2\n3\nnil\n");
+}
+
+#[test]
+fn roman_numeral_variables_are_only_bound_with_preset() {
+    let program = json!({"Application": [
+        {"Identifier": "add"}, {"Identifier": "x"}, {"Identifier": "v"}, {"Identifier": "i"}
+    ]});
+    assert_eq!(stdout(&run(&["--preset"], &program.to_string())), "16\n");
+    assert!(stderr(&run(&[], &program.to_string())).starts_with("Error: \"Unbound variable: x\"\n"));
+}
+
+#[test]
+fn display_prints_raw_text_and_write_string_the_escaped_form() {
+    let program = json!([
+        {"Application": [{"Identifier": "display"}, {"String": "a \"b\""}]},
+        {"Application": [{"Identifier": "write-string"}, {"String": "a \"b\""}]},
+        {"String": "c\n"}
+    ]);
+    assert_eq!(stdout(&run(&[], &program.to_string())), "a \"b\"\"a \\\"b\\\"\"\"c\\n\"\n");
+}
+
+#[test]
+fn read_number_rejects_non_finite_values() {
+    let program = json!({"Application": [{"Identifier": "read-number"}]}).to_string();
+    let path = temp_file("read-number", &program);
+    for input in ["NaN\n", "inf\n"] {
+        let output = run(&[path.to_str().unwrap()], input);
+        assert_eq!(stderr(&output), format!("Error: \"Not a number: {}\"\n", input.trim()));
+    }
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn runaway_recursion_is_a_clean_error_with_a_shortened_path() {
+    for args in [&[][..], &["--max-depth=100"]] {
+        let output = run(args, &runaway_recursion());
+        let message = stderr(&output);
+        assert!(message.starts_with("Error: \"Maximum recursion depth exceeded\"\n  at "), "{}", message);
+        assert!(message.contains(" more > "), "{}", message);
+        assert_eq!(message.lines().count(), 2);
+    }
+}
+
+#[test]
+fn max_depth_must_be_a_positive_number() {
+    for limit in ["0", "abc", "-5"] {
+        let output = run(&[&format!("--max-depth={}", limit)], r#"{"Number": 1}"#);
+        assert_eq!(output.status.code(), Some(1));
+        let expected = format!("Error: \"Invalid --max-depth: {} (expected a positive number)\"\n", limit);
+        assert_eq!(stderr(&output), expected);
+        assert_eq!(stdout(&output), "");
+    }
+}
+
+#[test]
+fn raising_max_depth_allows_deeper_recursion() {
+    // (define (depth n) (if (zero? n) 0 (add 1 (depth (sub n 1))))) (depth 12000)
+    let program = json!([
+        {"Define": [
+            {"Identifier": "depth"},
+            {"Lambda": [
+                {"Parameters": [{"Identifier": "n"}]},
+                {"If": [
+                    {"Application": [{"Identifier": "zero?"}, {"Identifier": "n"}]},
+                    {"Number": 0},
+                    {"Application": [
+                        {"Identifier": "add"}, {"Number": 1},
+                        {"Application": [
+                            {"Identifier": "depth"},
+                            {"Application": [{"Identifier": "sub"}, {"Identifier": "n"}, {"Number": 1}]}
+                        ]}
+                    ]}
+                ]}
+            ]}
+        ]},
+        {"Application": [{"Identifier": "depth"}, {"Number": 12000}]}
+    ])
+    .to_string();
+    assert!(stderr(&run(&[], &program)).starts_with("Error: \"Maximum recursion depth exceeded\""));
+    assert_eq!(stdout(&run(&["--max-depth=20000"], &program)), "12000\n");
+}
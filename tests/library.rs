@@ -0,0 +1,56 @@
+use interpreter::{eval_expr, Env, Expr, ResultValue, SharedEnv};
+use std::{cell::RefCell, rc::Rc};
+
+fn triple(args: Vec<ResultValue>) -> Result<ResultValue, String> {
+    match args[0] {
+        ResultValue::Number(n) => n.checked_mul(3).map(ResultValue::Number).ok_or_else(|| "Integer overflow".to_string()),
+        _ => Err("Invalid argument".to_string()),
+    }
+}
+
+fn eval_json(json: &str, env: &SharedEnv) -> Result<ResultValue, String> {
+    let expr: Expr = serde_json::from_str(json).expect("test program should parse");
+    eval_expr(expr, env).map_err(|e| e.message)
+}
+
+#[test]
+fn builtins_passed_to_new_are_callable_from_programs() {
+    let env = Rc::new(RefCell::new(Env::new(&[("triple", 1, triple)])));
+    let result = eval_json(r#"{"Application": [{"Identifier": "triple"}, {"Number": 14}]}"#, &env);
+    assert!(matches!(result, Ok(ResultValue::Number(42))));
+}
+
+#[test]
+fn registered_builtins_work_with_the_standard_ones() {
+    let env = Rc::new(RefCell::new(Env::new(&[])));
+    env.borrow_mut().register_builtin("triple", 1, triple);
+    let program = r#"{"Application": [{"Identifier": "map"}, {"Identifier": "triple"},
+        {"Application": [{"Identifier": "list"}, {"Number": 1}, {"Number": 2}]}]}"#;
+    let result = eval_json(program, &env).expect("map over a registered builtin");
+    assert_eq!(result.to_string(), "(3 6)");
+}
+
+#[test]
+fn registered_builtins_are_arity_checked() {
+    let env = Rc::new(RefCell::new(Env::new(&[("triple", 1, triple)])));
+    let result = eval_json(r#"{"Application": [{"Identifier": "triple"}, {"Number": 1}, {"Number": 2}]}"#, &env);
+    assert_eq!(result.unwrap_err(), "triple: expected 1 argument, got 2");
+}
+
+#[test]
+fn runaway_recursion_on_the_eval_stack_is_a_clean_error() {
+    // (define (loop n) (add 1 (loop n))) (loop 0), at the default depth limit
+    let program = r#"[
+        {"Define": [{"Identifier": "loop"}, {"Lambda": [{"Parameters": [{"Identifier": "n"}]},
+            {"Application": [{"Identifier": "add"}, {"Number": 1},
+                {"Application": [{"Identifier": "loop"}, {"Identifier": "n"}]}]}]}]},
+        {"Application": [{"Identifier": "loop"}, {"Number": 0}]}
+    ]"#;
+    let result = interpreter::with_eval_stack(|| {
+        let env = Rc::new(RefCell::new(Env::new(&[])));
+        let exprs: Vec<Expr> = serde_json::from_str(program).expect("test program should parse");
+        let result = exprs.into_iter().try_fold(ResultValue::Nil, |_, expr| eval_expr(expr, &env));
+        result.map(|value| value.to_string()).map_err(|e| e.message)
+    });
+    assert_eq!(result.expect("the evaluation thread should start").unwrap_err(), "Maximum recursion depth exceeded");
+}